@@ -9,9 +9,7 @@ async function main() {
         url: "ws://108.174.195.143:8000"
     });
 
-    const defaults = JSON.parse(fs.readFileSync("./patterns/defaults.json"));
-
-
+    // Args left out of a pattern file take the defaults listed by describe_patterns
     client.on("set_pattern", meta => {
         console.log("Setting pattern to: ", meta.toLowerCase().replace(" ", "_"));
 
@@ -23,14 +21,12 @@ async function main() {
                 const patternObj = {
                     "pattern": pattern["pattern"],
                     "name": name,
-                    "args": {...defaults[pattern["pattern"]], ...pattern["args"]}
+                    "args": pattern["args"] || {}
                 };
                 req.push(patternObj);
             }
-            client.send("clear_patterns", {}, () => {
-                client.send("add_patterns", {patterns: req}, patterns => {
-                    console.log("Patterns are: ", JSON.parse(patterns));
-                })
+            client.send("transition", {patterns: req, style: "crossfade", duration: 1.0}, patterns => {
+                console.log("Patterns are: ", JSON.parse(patterns));
             })
         }
     });
@@ -0,0 +1,214 @@
+// Runs the pattern engine against a virtual strip so the relay and UI can be developed
+// without a Pi. Commands use the same names and payloads as the websocket protocol, one
+// JSON object per line: {"command": "add_pattern", "payload": {...}}
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Mutex, Arc};
+use std::thread;
+
+use leds::commands::{CommandError, Commands};
+use leds::input::{DdpServer, OpcServer, WledServer};
+use leds::output::{CaptureOutput, Output, TerminalOutput};
+use leds::pattern::{Runner, PatternManager};
+
+const USAGE: &str = "Usage: simulator [--leds <count>] [--listen <addr>] [--columns <count>] [--fps <rate>] [--opc <addr>] [--ddp <addr>] [--wled <addr>] [--headless]";
+
+struct Options {
+    leds: usize,
+    listen: Option<String>,
+    columns: Option<usize>,
+    fps: u32,
+    opc: Option<String>,
+    ddp: Option<String>,
+    wled: Option<String>,
+    headless: bool,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    command: String,
+    #[serde(default)]
+    payload: Value,
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        leds: 240,
+        listen: None,
+        columns: None,
+        fps: 60,
+        opc: None,
+        ddp: None,
+        wled: None,
+        headless: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--leds" => options.leds = parse_value(&arg, args.next()),
+            "--listen" => options.listen = Some(parse_value(&arg, args.next())),
+            "--columns" => options.columns = Some(parse_value(&arg, args.next())),
+            "--fps" => options.fps = parse_value(&arg, args.next()),
+            "--opc" => options.opc = Some(parse_value(&arg, args.next())),
+            "--ddp" => options.ddp = Some(parse_value(&arg, args.next())),
+            "--wled" => options.wled = Some(parse_value(&arg, args.next())),
+            "--headless" => options.headless = true,
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(if arg == "--help" { 0 } else { 1 });
+            }
+        }
+    }
+    options
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    match value.and_then(|value| value.parse().ok()) {
+        Some(value) => value,
+        None => {
+            eprintln!("Missing or invalid value for {}\n{}", flag, USAGE);
+            process::exit(1);
+        }
+    }
+}
+
+// Answers one request per line until the reader closes
+fn serve<R: BufRead, W: Write>(commands: &Commands, reader: R, mut writer: W) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => commands.handle(&request.command, request.payload),
+            Err(err) => {
+                eprintln!("Could not parse request: {}", err);
+                Some(CommandError::InvalidPayload(err).to_json().to_string())
+            }
+        };
+        let response = response.unwrap_or_else(|| "null".to_string());
+        if writeln!(writer, "{}", response).and_then(|_| writer.flush()).is_err() {
+            break;
+        }
+    }
+}
+
+fn main() {
+    let options = parse_options();
+
+    // The preview goes to stderr so stdout stays free for command responses
+    let output: Box<dyn Output> = if options.headless {
+        let (capture, _frames) = CaptureOutput::with_limit(options.leds, 1);
+        Box::new(capture)
+    } else {
+        let mut terminal = TerminalOutput::stderr(options.leds).max_fps(options.fps);
+        if let Some(columns) = options.columns {
+            terminal = terminal.columns(columns);
+        }
+        Box::new(terminal)
+    };
+
+    let manager = PatternManager::new(output);
+    let arc_manager = Arc::new(Mutex::new(manager));
+    let mut runner = Runner::new();
+    runner.start(Arc::clone(&arc_manager));
+
+    if let Some(addr) = options.opc {
+        let server = OpcServer::bind(&addr).unwrap_or_else(|err| {
+            eprintln!("Could not listen for OPC on {}: {}", addr, err);
+            process::exit(1);
+        });
+        server.start(Arc::clone(&arc_manager));
+    }
+    if let Some(addr) = options.ddp {
+        let server = DdpServer::bind(&addr).unwrap_or_else(|err| {
+            eprintln!("Could not listen for DDP on {}: {}", addr, err);
+            process::exit(1);
+        });
+        server.start(Arc::clone(&arc_manager));
+    }
+    if let Some(addr) = options.wled {
+        let server = WledServer::bind(&addr).unwrap_or_else(|err| {
+            eprintln!("Could not listen for WLED realtime on {}: {}", addr, err);
+            process::exit(1);
+        });
+        server.start(Arc::clone(&arc_manager));
+    }
+
+    let commands = Arc::new(Commands::new(Arc::clone(&arc_manager)));
+
+    match options.listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(&addr).unwrap_or_else(|err| {
+                eprintln!("Could not listen on {}: {}", addr, err);
+                process::exit(1);
+            });
+            eprintln!("Listening for commands on {}", addr);
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("Connection failed: {}", err);
+                        continue;
+                    }
+                };
+                let commands = Arc::clone(&commands);
+                thread::spawn(move || {
+                    let reader = match stream.try_clone() {
+                        Ok(reader) => BufReader::new(reader),
+                        Err(_) => return,
+                    };
+                    serve(&commands, reader, stream);
+                });
+            }
+        }
+        None => {
+            let stdin = io::stdin();
+            serve(&commands, stdin.lock(), io::stdout());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leds::output::CaptureOutput;
+
+    fn replies(input: &str) -> Vec<String> {
+        let (output, _frames) = CaptureOutput::new(4);
+        let commands = Commands::new(Arc::new(Mutex::new(PatternManager::new(Box::new(output)))));
+        let mut written = Vec::new();
+        serve(&commands, input.as_bytes(), &mut written);
+        String::from_utf8(written).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn writes_one_reply_per_request() {
+        let input = concat!(
+            r#"{"command": "add_patterns", "payload": {"patterns": [{"pattern": "solid", "name": "a", "args": {}}]}}"#, "\n",
+            "\n",
+            r#"{"command": "nope"}"#, "\n",
+        );
+        let replies = replies(input);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0], r#"["a"]"#);
+        assert!(replies[1].contains("unknown_command"), "{}", replies[1]);
+    }
+
+    #[test]
+    fn answers_lines_that_are_not_requests() {
+        for line in ["not json", r#"{"payload": {}}"#].iter() {
+            let reply: Value = serde_json::from_str(&replies(line)[0]).unwrap();
+            assert_eq!(reply["error"]["kind"], "invalid_payload");
+        }
+    }
+}
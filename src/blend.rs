@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+// How a layer is combined with the layers composited beneath it. Patterns have no alpha
+// channel, so the modes that need one take it from the brightest channel of the layer.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    // Sum the layers, scaling the pixel back down if a channel overflows
+    #[default]
+    Add,
+    // Keep the brighter of the two values per channel
+    Max,
+    // Paint the layer on top, letting dark pixels show what is underneath
+    AlphaOver,
+    Multiply,
+    Screen,
+    Subtract,
+}
+
+impl BlendMode {
+    // Channels are in the 0 to 1 range, except that a stack of Add layers may run over 1
+    // until it is normalised
+    pub fn blend(&self, under: [f32; 4], over: [f32; 4]) -> [f32; 4] {
+        let under = match *self {
+            BlendMode::Add => under,
+            _ => normalise(under),
+        };
+        let alpha = over.iter().cloned().fold(0.0, f32::max).min(1.0);
+        let mut out = [0.0; 4];
+        for l in 0..4 {
+            let (a, b) = (under[l], over[l]);
+            out[l] = match *self {
+                BlendMode::Add => a + b,
+                BlendMode::Max => a.max(b),
+                BlendMode::AlphaOver => b + a * (1.0 - alpha),
+                BlendMode::Multiply => a * b,
+                BlendMode::Screen => 1.0 - (1.0 - a) * (1.0 - b),
+                BlendMode::Subtract => (a - b).max(0.0),
+            };
+        }
+        out
+    }
+}
+
+// Blends a layer with the given opacity, 0 leaving `under` untouched
+pub fn blend_layer(mode: BlendMode, opacity: f32, under: [f32; 4], over: [f32; 4]) -> [f32; 4] {
+    let blended = mode.blend(under, over);
+    let mut out = [0.0; 4];
+    for l in 0..4 {
+        out[l] = under[l] + (blended[l] - under[l]) * opacity;
+    }
+    out
+}
+
+// Scales the pixel down so no channel is over 1, keeping its hue
+pub fn normalise(led: [f32; 4]) -> [f32; 4] {
+    let max = led.iter().cloned().fold(0.0, f32::max);
+    if max <= 1.0 {
+        return led;
+    }
+    let mut out = led;
+    for channel in out.iter_mut() {
+        *channel /= max;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNDER: [f32; 4] = [0.5, 0.2, 0.0, 0.0];
+    const OVER: [f32; 4] = [0.25, 0.5, 0.0, 0.0];
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn blends_each_mode() {
+        assert_close(BlendMode::Add.blend(UNDER, OVER), [0.75, 0.7, 0.0, 0.0]);
+        assert_close(BlendMode::Max.blend(UNDER, OVER), [0.5, 0.5, 0.0, 0.0]);
+        // Alpha is the brightest channel of the layer on top, here a half
+        assert_close(BlendMode::AlphaOver.blend(UNDER, OVER), [0.5, 0.6, 0.0, 0.0]);
+        assert_close(BlendMode::Multiply.blend(UNDER, OVER), [0.125, 0.1, 0.0, 0.0]);
+        assert_close(BlendMode::Screen.blend(UNDER, OVER), [0.625, 0.6, 0.0, 0.0]);
+        assert_close(BlendMode::Subtract.blend(UNDER, OVER), [0.25, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn alpha_over_shows_through_black() {
+        assert_close(BlendMode::AlphaOver.blend(UNDER, [0.0; 4]), UNDER);
+        assert_close(BlendMode::AlphaOver.blend(UNDER, [0.0, 0.0, 1.0, 0.0]), [0.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn only_add_keeps_overflow() {
+        let over_full = [1.5, 0.75, 0.0, 0.0];
+        assert_close(BlendMode::Add.blend(over_full, [0.5, 0.0, 0.0, 0.0]), [2.0, 0.75, 0.0, 0.0]);
+        // Other modes work on the stack as it will be shown
+        assert_close(BlendMode::Max.blend(over_full, [0.0; 4]), [1.0, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn opacity_mixes_with_what_is_under() {
+        assert_close(blend_layer(BlendMode::Add, 0.0, UNDER, OVER), UNDER);
+        assert_close(blend_layer(BlendMode::Add, 1.0, UNDER, OVER), [0.75, 0.7, 0.0, 0.0]);
+        assert_close(blend_layer(BlendMode::Multiply, 0.5, UNDER, OVER), [0.3125, 0.15, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn normalise_keeps_the_hue() {
+        assert_close(normalise([2.0, 1.0, 0.5, 0.0]), [1.0, 0.5, 0.25, 0.0]);
+        assert_close(normalise(UNDER), UNDER);
+    }
+
+    #[test]
+    fn deserializes_snake_case() {
+        assert_eq!(serde_json::from_str::<BlendMode>(r#""alpha_over""#).unwrap(), BlendMode::AlphaOver);
+        assert_eq!(BlendMode::default(), BlendMode::Add);
+    }
+}
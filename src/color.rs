@@ -0,0 +1,244 @@
+use hsl::HSL;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+use std::fmt;
+use std::str::FromStr;
+
+// An RGBW colour. Everything above the output stage works in this type; the order the
+// channels go out on the wire is left to each Output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+const NAMED: [(&str, Color); 20] = [
+    ("black", Color::rgb(0, 0, 0)),
+    ("white", Color::rgb(255, 255, 255)),
+    ("warm_white", Color::rgbw(0, 0, 0, 255)),
+    ("red", Color::rgb(255, 0, 0)),
+    ("green", Color::rgb(0, 255, 0)),
+    ("blue", Color::rgb(0, 0, 255)),
+    ("yellow", Color::rgb(255, 255, 0)),
+    ("cyan", Color::rgb(0, 255, 255)),
+    ("magenta", Color::rgb(255, 0, 255)),
+    ("orange", Color::rgb(255, 165, 0)),
+    ("gold", Color::rgb(255, 215, 0)),
+    ("purple", Color::rgb(128, 0, 128)),
+    ("violet", Color::rgb(238, 130, 238)),
+    ("pink", Color::rgb(255, 192, 203)),
+    ("hot_pink", Color::rgb(255, 105, 180)),
+    ("teal", Color::rgb(0, 128, 128)),
+    ("turquoise", Color::rgb(64, 224, 208)),
+    ("indigo", Color::rgb(75, 0, 130)),
+    ("amber", Color::rgb(255, 191, 0)),
+    ("crimson", Color::rgb(220, 20, 60)),
+];
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b, w: 0 }
+    }
+
+    pub const fn rgbw(r: u8, g: u8, b: u8, w: u8) -> Color {
+        Color { r, g, b, w }
+    }
+
+    // Hue in degrees, saturation and lightness from 0 to 1
+    pub fn from_hsl(h: f64, s: f64, l: f64) -> Color {
+        let (r, g, b) = HSL { h: h.rem_euclid(360.0), s, l }.to_rgb();
+        Color::rgb(r, g, b)
+    }
+
+    // Hue in degrees, saturation and value from 0 to 1
+    pub fn from_hsv(h: f64, s: f64, v: f64) -> Color {
+        let h = h.rem_euclid(360.0) / 60.0;
+        let c = v * s;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = v - c;
+        Color::rgb(to_channel(r + m), to_channel(g + m), to_channel(b + m))
+    }
+
+    // Accepts #rgb, #rrggbb and #rrggbbww, with or without the #
+    pub fn from_hex(hex: &str) -> Option<Color> {
+        let hex = hex.trim_start_matches('#');
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        match hex.len() {
+            3 => {
+                let short = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|v| v * 17);
+                Some(Color::rgb(short(0)?, short(1)?, short(2)?))
+            }
+            6 => Some(Color::rgb(channel(0)?, channel(2)?, channel(4)?)),
+            8 => Some(Color::rgbw(channel(0)?, channel(2)?, channel(4)?, channel(6)?)),
+            _ => None,
+        }
+    }
+
+    pub fn named(name: &str) -> Option<Color> {
+        let name = name.to_lowercase().replace(' ', "_");
+        NAMED.iter()
+            .find(|&&(known, _)| known == name)
+            .map(|&(_, color)| color)
+    }
+
+    // Multiplies every channel by `factor`, which is expected to be between 0 and 1
+    pub fn scale(self, factor: f64) -> Color {
+        let scale = |v: u8| (v as f64 * factor).clamp(0.0, 255.0) as u8;
+        Color::rgbw(scale(self.r), scale(self.g), scale(self.b), scale(self.w))
+    }
+
+    pub fn to_hex(&self) -> String {
+        if self.w == 0 {
+            format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+        } else {
+            format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.w)
+        }
+    }
+
+    pub fn channels(&self) -> [u8; 4] {
+        [self.r, self.g, self.b, self.w]
+    }
+
+    pub fn from_channels(channels: [u8; 4]) -> Color {
+        Color::rgbw(channels[0], channels[1], channels[2], channels[3])
+    }
+}
+
+fn to_channel(value: f64) -> u8 {
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[derive(Debug)]
+pub struct ParseColorError(String);
+
+impl fmt::Display for ParseColorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not a hex code or colour name: {}", self.0)
+    }
+}
+
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    fn from_str(s: &str) -> Result<Color, ParseColorError> {
+        Color::named(s)
+            .or_else(|| Color::from_hex(s))
+            .ok_or_else(|| ParseColorError(s.to_string()))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+// Every way a colour can be written in JSON
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorRepr {
+    Text(String),
+    Channels(Vec<u8>),
+    Rgbw { r: u8, g: u8, b: u8, #[serde(default)] w: u8 },
+    Hsl { h: f64, s: f64, l: f64 },
+    Hsv { h: f64, s: f64, v: f64 },
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+        match ColorRepr::deserialize(deserializer)? {
+            ColorRepr::Text(text) => text.parse().map_err(D::Error::custom),
+            ColorRepr::Channels(channels) => match channels.len() {
+                3 => Ok(Color::rgb(channels[0], channels[1], channels[2])),
+                4 => Ok(Color::rgbw(channels[0], channels[1], channels[2], channels[3])),
+                len => Err(D::Error::invalid_length(len, &"[r, g, b] or [r, g, b, w]")),
+            },
+            ColorRepr::Rgbw { r, g, b, w } => Ok(Color::rgbw(r, g, b, w)),
+            ColorRepr::Hsl { h, s, l } => Ok(Color::from_hsl(h, s, l)),
+            ColorRepr::Hsv { h, s, v } => Ok(Color::from_hsv(h, s, v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(value: serde_json::Value) -> Result<Color, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    #[test]
+    fn parses_hex_codes() {
+        assert_eq!(Color::from_hex("#f80"), Some(Color::rgb(255, 136, 0)));
+        assert_eq!(Color::from_hex("0a0b0c"), Some(Color::rgb(10, 11, 12)));
+        assert_eq!(Color::from_hex("#0a0b0c0d"), Some(Color::rgbw(10, 11, 12, 13)));
+        assert_eq!(Color::from_hex("#0a0b0"), None);
+        assert_eq!(Color::from_hex("#gg0000"), None);
+        assert_eq!(Color::from_hex("#é0000"), None);
+        assert_eq!(Color::from_hex(""), None);
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!("Hot Pink".parse::<Color>().unwrap(), Color::rgb(255, 105, 180));
+        assert_eq!("warm_white".parse::<Color>().unwrap(), Color::rgbw(0, 0, 0, 255));
+        assert!("lime".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn deserializes_every_form() {
+        assert_eq!(parse(json!("#010203")).unwrap(), Color::rgb(1, 2, 3));
+        assert_eq!(parse(json!("red")).unwrap(), Color::rgb(255, 0, 0));
+        assert_eq!(parse(json!([1, 2, 3])).unwrap(), Color::rgb(1, 2, 3));
+        assert_eq!(parse(json!([1, 2, 3, 4])).unwrap(), Color::rgbw(1, 2, 3, 4));
+        assert_eq!(parse(json!({"r": 1, "g": 2, "b": 3})).unwrap(), Color::rgb(1, 2, 3));
+        assert_eq!(parse(json!({"h": 120.0, "s": 1.0, "l": 0.5})).unwrap(), Color::rgb(0, 255, 0));
+        assert_eq!(parse(json!({"h": 240.0, "s": 1.0, "v": 0.5})).unwrap(), Color::rgb(0, 0, 128));
+    }
+
+    #[test]
+    fn rejects_bad_colours() {
+        assert!(parse(json!([1, 2])).is_err());
+        assert!(parse(json!([1, 2, 256])).is_err());
+        assert!(parse(json!("nope")).is_err());
+        assert!(parse(json!({"r": 1})).is_err());
+        assert!(parse(json!(7)).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_hex() {
+        for color in [Color::rgb(1, 2, 3), Color::rgbw(250, 0, 16, 9)].iter() {
+            assert_eq!(parse(json!(color)).unwrap(), *color);
+        }
+        assert_eq!(Color::rgbw(1, 2, 3, 0).to_string(), "#010203");
+    }
+
+    #[test]
+    fn wraps_hues_and_scales() {
+        assert_eq!(Color::from_hsv(-120.0, 1.0, 1.0), Color::from_hsv(240.0, 1.0, 1.0));
+        assert_eq!(Color::rgbw(200, 100, 0, 50).scale(0.5), Color::rgbw(100, 50, 0, 25));
+    }
+}
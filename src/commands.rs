@@ -0,0 +1,653 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard, Arc, PoisonError};
+use std::time::Duration;
+
+use crate::blend::BlendMode;
+use crate::correction::Correction;
+use crate::pattern::{self, merge, ManagerState, Pattern, PatternManager};
+use crate::region::Region;
+use crate::registry::{PatternRegistry, RegistryError};
+use crate::transition::TransitionStyle;
+
+// Every command understood by the daemon. Both the websocket client and the simulator
+// register these names and forward the payloads to Commands::handle.
+pub const COMMANDS: [&str; 22] = [
+    "add_pattern", "add_patterns", "clear_patterns", "set_brightness", "set_correction", "get_power",
+    "set_blend", "raise_pattern", "lower_pattern", "set_z_index", "transition", "remove_pattern",
+    "pause_pattern", "resume_pattern", "mute_pattern", "unmute_pattern", "solo_pattern", "unsolo_pattern",
+    "set_pattern_params", "describe_patterns", "get_state", "set_state",
+];
+
+#[derive(Debug)]
+pub enum CommandError {
+    UnknownCommand(String),
+    UnknownZone(usize),
+    InvalidPayload(serde_json::Error),
+    Pattern(RegistryError),
+    OutOfRange(String),
+    NotFound(String),
+    // Arguments that don't fit what they're applied to, with what they were for
+    InvalidArgs(String, String),
+    // A handler panicked
+    Internal(String),
+}
+
+impl CommandError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CommandError::UnknownCommand(_) => "unknown_command",
+            CommandError::UnknownZone(_) => "unknown_zone",
+            CommandError::InvalidPayload(_) => "invalid_payload",
+            CommandError::Pattern(RegistryError::UnknownPattern(_)) => "unknown_pattern",
+            CommandError::Pattern(RegistryError::InvalidArgs(..)) => "invalid_args",
+            CommandError::OutOfRange(_) => "out_of_range",
+            CommandError::NotFound(_) => "not_found",
+            CommandError::InvalidArgs(..) => "invalid_args",
+            CommandError::Internal(_) => "internal",
+        }
+    }
+
+    // The reply sent back in place of the command's result
+    pub fn to_json(&self) -> Value {
+        json!({ "error": { "kind": self.kind(), "message": self.to_string() } })
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand(command) => write!(f, "unknown command {:?}", command),
+            CommandError::UnknownZone(zone) => write!(f, "unknown zone {}", zone),
+            CommandError::InvalidPayload(err) => write!(f, "invalid payload: {}", err),
+            CommandError::Pattern(err) => write!(f, "{}", err),
+            CommandError::OutOfRange(message) => write!(f, "{}", message),
+            CommandError::NotFound(name) => write!(f, "no pattern named {:?}", name),
+            CommandError::InvalidArgs(name, err) => write!(f, "invalid args for {}: {}", name, err),
+            CommandError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl From<RegistryError> for CommandError {
+    fn from(err: RegistryError) -> CommandError {
+        CommandError::Pattern(err)
+    }
+}
+
+type Reply = Result<String, CommandError>;
+
+#[derive(Deserialize)]
+struct Blank {}
+
+#[derive(Deserialize)]
+struct AddPattern {
+    pattern: String,
+    name: String,
+    args: Value,
+    #[serde(default)]
+    blend: BlendMode,
+    #[serde(default = "full_opacity")]
+    opacity: f32,
+    #[serde(default)]
+    region: Region,
+}
+
+#[derive(Deserialize)]
+struct SetBlend {
+    name: String,
+    #[serde(default)]
+    blend: BlendMode,
+    #[serde(default = "full_opacity")]
+    opacity: f32,
+}
+
+#[derive(Deserialize)]
+struct PatternName { name: String }
+
+#[derive(Deserialize)]
+struct SetPatternParams { name: String, params: Value }
+
+#[derive(Deserialize)]
+struct SetZIndex { name: String, z_index: i32 }
+
+fn full_opacity() -> f32 {
+    1.0
+}
+
+fn default_transition() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct AddPatterns { patterns: Vec<AddPattern> }
+
+#[derive(Deserialize)]
+struct StartTransition {
+    patterns: Vec<AddPattern>,
+    #[serde(default)]
+    style: TransitionStyle,
+    #[serde(default = "default_transition")]
+    duration: f64,
+}
+
+#[derive(Deserialize)]
+struct SetBrightness { brightness: f64 }
+
+// Routes commands to the manager of the zone they name with an optional "zone" index in
+// the payload, defaulting to the first zone.
+pub struct Commands {
+    zones: Vec<Arc<Mutex<PatternManager>>>,
+    registry: PatternRegistry,
+}
+
+impl Commands {
+    pub fn new(manager: Arc<Mutex<PatternManager>>) -> Commands {
+        Commands::with_zones(vec![manager])
+    }
+
+    pub fn with_zones(zones: Vec<Arc<Mutex<PatternManager>>>) -> Commands {
+        Commands { zones, registry: PatternRegistry::default() }
+    }
+
+    // Replaces the built in pattern types, e.g. with a registry that has extra patterns
+    pub fn registry(mut self, registry: PatternRegistry) -> Commands {
+        self.registry = registry;
+        self
+    }
+
+    // Errors are logged and returned as {"error": {"kind", "message"}} so a bad request never
+    // takes the daemon down
+    pub fn handle(&self, command: &str, payload: Value) -> Option<String> {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.dispatch(command, payload)))
+            .unwrap_or_else(|panic| {
+                let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "handler panicked".to_string());
+                Err(CommandError::Internal(message))
+            });
+        match result {
+            Ok(reply) => Some(reply),
+            Err(err) => {
+                eprintln!("Failed to handle {}: {}", command, err);
+                Some(err.to_json().to_string())
+            }
+        }
+    }
+
+    fn dispatch(&self, command: &str, payload: Value) -> Reply {
+        let zone = match payload.get("zone") {
+            Some(zone) => parse::<usize>(zone.clone())?,
+            None => 0,
+        };
+        let manager = self.zones.get(zone).ok_or(CommandError::UnknownZone(zone))?;
+
+        match command {
+            "add_pattern" => self.add_pattern(manager, parse(payload)?),
+            "add_patterns" => self.add_patterns(manager, parse(payload)?),
+            "clear_patterns" => Commands::clear_patterns(manager, parse(payload)?),
+            "set_brightness" => Commands::set_brightness(manager, parse(payload)?),
+            "set_correction" => Commands::set_correction(manager, parse(payload)?),
+            "get_power" => Commands::get_power(manager, parse(payload)?),
+            "set_blend" => Commands::set_blend(manager, parse(payload)?),
+            "raise_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.raise_pattern(name)),
+            "lower_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.lower_pattern(name)),
+            "set_z_index" => Commands::set_z_index(manager, parse(payload)?),
+            "transition" => self.transition(manager, parse(payload)?),
+            "remove_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.remove_pattern(name.to_string())),
+            "pause_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.set_paused(name, true)),
+            "resume_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.set_paused(name, false)),
+            "mute_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.set_muted(name, true)),
+            "unmute_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.set_muted(name, false)),
+            "solo_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.set_solo(name, true)),
+            "unsolo_pattern" => Commands::update_layer(manager, parse(payload)?, |p_manager, name| p_manager.set_solo(name, false)),
+            "set_pattern_params" => Commands::set_pattern_params(manager, parse(payload)?),
+            "describe_patterns" => self.describe_patterns(parse(payload)?),
+            "get_state" => Commands::get_state(manager, parse(payload)?),
+            "set_state" => self.set_state(manager, parse(payload)?),
+            _ => Err(CommandError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    fn add_pattern(&self, manager: &Mutex<PatternManager>, val: AddPattern) -> Reply {
+        let patterns = std::slice::from_ref(&val);
+        let built = self.build_layers(patterns)?;
+        let mut p_manager = lock(manager);
+        Commands::add_layers(&mut p_manager, patterns, built);
+        Ok(json!(p_manager.get_patterns()).to_string())
+    }
+
+    fn add_patterns(&self, manager: &Mutex<PatternManager>, val: AddPatterns) -> Reply {
+        let built = self.build_layers(&val.patterns)?;
+        eprintln!("Manager Locked to Add Patterns");
+        let mut p_manager = lock(manager);
+        Commands::add_layers(&mut p_manager, &val.patterns, built);
+        let ret_val = Ok(json!(p_manager.get_patterns()).to_string());
+        drop(p_manager);
+        eprintln!("Manager to add pattern unlocked");
+        ret_val
+    }
+
+    // Swaps the whole layer set for the one in the payload over `duration` seconds
+    fn transition(&self, manager: &Mutex<PatternManager>, val: StartTransition) -> Reply {
+        check_range("duration", val.duration, 0.0, 3600.0)?;
+        let built = self.build_layers(&val.patterns)?;
+        let mut p_manager = lock(manager);
+        p_manager.start_transition(val.style, Duration::from_secs_f64(val.duration));
+        Commands::add_layers(&mut p_manager, &val.patterns, built);
+        Ok(json!(p_manager.get_patterns()).to_string())
+    }
+
+    // Builds and checks every pattern before the manager is touched, so one bad entry leaves
+    // the layers as they were
+    fn build_layers(&self, patterns: &[AddPattern]) -> Result<Vec<Box<dyn Pattern>>, CommandError> {
+        patterns.iter()
+            .map(|n_pattern| {
+                check_range("opacity", n_pattern.opacity as f64, 0.0, 1.0)?;
+                Ok(self.registry.build(&n_pattern.pattern, n_pattern.args.clone())?)
+            })
+            .collect()
+    }
+
+    fn add_layers(p_manager: &mut PatternManager, patterns: &[AddPattern], built: Vec<Box<dyn Pattern>>) {
+        for (n_pattern, pattern) in patterns.iter().zip(built) {
+            p_manager.add_pattern_with_region(n_pattern.name.clone(), pattern, n_pattern.region.clone());
+            p_manager.set_blend(&n_pattern.name, n_pattern.blend, n_pattern.opacity);
+            p_manager.set_pattern_type(&n_pattern.name, &n_pattern.pattern);
+        }
+    }
+
+    fn clear_patterns(manager: &Mutex<PatternManager>, _: Blank) -> Reply {
+        eprintln!("Manage locked to clear patterns");
+        lock(manager).clear();
+        Ok("Cleared".to_string())
+    }
+
+    fn set_brightness(manager: &Mutex<PatternManager>, val: SetBrightness) -> Reply {
+        check_range("brightness", val.brightness, 0.0, 1.0)?;
+        lock(manager).set_brightness(val.brightness);
+        Ok(format!("Set brightness to: {}", val.brightness))
+    }
+
+    // Fields left out of the payload keep their current value
+    fn set_correction(manager: &Mutex<PatternManager>, val: Value) -> Reply {
+        let mut p_manager = lock(manager);
+        let mut correction = json!(p_manager.get_correction());
+        let mut val = val;
+        // The zone only routes the command, it isn't part of the correction
+        if let Some(fields) = val.as_object_mut() {
+            fields.remove("zone");
+        }
+        merge(&mut correction, &val, "").map_err(|err| CommandError::InvalidArgs("correction".to_string(), err))?;
+        let correction: Correction = parse(correction)?;
+        check_range("gamma", correction.gamma, 0.1, 5.0)?;
+        let scale = correction.scale;
+        for (field, value) in [("scale.r", scale.r), ("scale.g", scale.g), ("scale.b", scale.b), ("scale.w", scale.w)].iter() {
+            check_range(field, *value, 0.0, 1.0)?;
+        }
+        p_manager.set_correction(correction);
+        Ok(json!(correction).to_string())
+    }
+
+    fn set_blend(manager: &Mutex<PatternManager>, val: SetBlend) -> Reply {
+        check_range("opacity", val.opacity as f64, 0.0, 1.0)?;
+        let mut p_manager = lock(manager);
+        if !p_manager.set_blend(&val.name, val.blend, val.opacity) {
+            return Err(CommandError::NotFound(val.name));
+        }
+        Ok(json!(p_manager.get_patterns()).to_string())
+    }
+
+    fn set_z_index(manager: &Mutex<PatternManager>, val: SetZIndex) -> Reply {
+        let mut p_manager = lock(manager);
+        if !p_manager.set_z_index(&val.name, val.z_index) {
+            return Err(CommandError::NotFound(val.name));
+        }
+        Ok(json!(p_manager.get_patterns()).to_string())
+    }
+
+    // Applies a change to the named layer and replies with the layer list
+    fn update_layer<F>(manager: &Mutex<PatternManager>, val: PatternName, update: F) -> Reply
+    where
+        F: FnOnce(&mut PatternManager, &str) -> bool,
+    {
+        let mut p_manager = lock(manager);
+        if !update(&mut p_manager, &val.name) {
+            return Err(CommandError::NotFound(val.name));
+        }
+        Ok(json!(p_manager.get_patterns()).to_string())
+    }
+
+    // Params left out of the patch keep their current value. Replies with the full set.
+    fn set_pattern_params(manager: &Mutex<PatternManager>, val: SetPatternParams) -> Reply {
+        let SetPatternParams { name, params } = val;
+        let mut p_manager = lock(manager);
+        if p_manager.get_pattern_params(&name).is_none() {
+            return Err(CommandError::NotFound(name));
+        }
+        p_manager.set_pattern_params(&name, params)
+            .map_err(|err| CommandError::InvalidArgs(name.clone(), err))?;
+        Ok(json!(p_manager.get_pattern_params(&name)).to_string())
+    }
+
+    fn describe_patterns(&self, _: Blank) -> Reply {
+        Ok(json!(self.registry.describe()).to_string())
+    }
+
+    fn get_state(manager: &Mutex<PatternManager>, _: Blank) -> Reply {
+        Ok(json!(lock(manager).get_state()).to_string())
+    }
+
+    // Rebuilds every layer before the manager is touched, so a snapshot that doesn't load
+    // leaves the strip as it was. Replies with the state as restored.
+    fn set_state(&self, manager: &Mutex<PatternManager>, val: ManagerState) -> Reply {
+        check_range("brightness", val.brightness, 0.0, 1.0)?;
+        let built = val.layers.iter()
+            .map(|layer| {
+                check_range("opacity", layer.opacity as f64, 0.0, 1.0)?;
+                let pattern_type = layer.pattern.as_deref().unwrap_or_default();
+                Ok(self.registry.build(pattern_type, layer.params.clone())?)
+            })
+            .collect::<Result<Vec<_>, CommandError>>()?;
+        let mut p_manager = lock(manager);
+        p_manager.set_state(&val, built);
+        Ok(json!(p_manager.get_state()).to_string())
+    }
+
+    fn get_power(manager: &Mutex<PatternManager>, _: Blank) -> Reply {
+        Ok(json!(lock(manager).get_power()).to_string())
+    }
+}
+
+fn parse<T: DeserializeOwned>(payload: Value) -> Result<T, CommandError> {
+    serde_json::from_value(payload).map_err(CommandError::InvalidPayload)
+}
+
+fn check_range(field: &str, value: f64, min: f64, max: f64) -> Result<(), CommandError> {
+    pattern::check_range(field, value, min, max).map_err(CommandError::OutOfRange)
+}
+
+// A handler that panicked while holding the lock poisons it, but the manager is still in a
+// usable state so carry on with it rather than failing every later command
+fn lock(manager: &Mutex<PatternManager>) -> MutexGuard<'_, PatternManager> {
+    manager.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::output::{CaptureOutput, FrameCapture};
+
+    fn commands(num_leds: usize) -> (Commands, Arc<Mutex<PatternManager>>, FrameCapture) {
+        let (output, capture) = CaptureOutput::new(num_leds);
+        let manager = Arc::new(Mutex::new(PatternManager::new(Box::new(output))));
+        (Commands::new(Arc::clone(&manager)), manager, capture)
+    }
+
+    // The reply as JSON, or as a JSON string for the replies that are plain text
+    fn send(commands: &Commands, command: &str, payload: Value) -> Value {
+        let reply = commands.handle(command, payload).unwrap();
+        serde_json::from_str(&reply).unwrap_or(Value::String(reply))
+    }
+
+    fn error_kind(reply: &Value) -> &str {
+        reply["error"]["kind"].as_str().unwrap_or_default()
+    }
+
+    #[test]
+    fn routes_commands_by_zone() {
+        let (first, first_capture) = CaptureOutput::new(1);
+        let (second, second_capture) = CaptureOutput::new(1);
+        let zones = vec![
+            Arc::new(Mutex::new(PatternManager::new(Box::new(first)))),
+            Arc::new(Mutex::new(PatternManager::new(Box::new(second)))),
+        ];
+        let commands = Commands::with_zones(zones.clone());
+
+        send(&commands, "add_pattern", json!({ "name": "a", "pattern": "solid", "args": { "color": "red" } }));
+        send(&commands, "add_pattern", json!({ "zone": 1, "name": "b", "pattern": "solid", "args": { "color": "blue" } }));
+        assert_eq!(lock(&zones[0]).get_patterns(), vec!["a".to_string()]);
+        assert_eq!(lock(&zones[1]).get_patterns(), vec!["b".to_string()]);
+        lock(&zones[0]).advance(1);
+        lock(&zones[1]).advance(1);
+        assert_eq!(first_capture.last().unwrap().leds, vec![Color::rgb(255, 0, 0)]);
+        assert_eq!(second_capture.last().unwrap().leds, vec![Color::rgb(0, 0, 255)]);
+
+        for zone in [json!("1"), json!(-1), json!(1.5), json!(null)].iter() {
+            let reply = send(&commands, "add_pattern", json!({ "zone": zone, "name": "c", "pattern": "solid", "args": {} }));
+            assert_eq!(error_kind(&reply), "invalid_payload", "{}", zone);
+        }
+        let reply = send(&commands, "add_pattern", json!({ "zone": 2, "name": "c", "pattern": "solid", "args": {} }));
+        assert_eq!(error_kind(&reply), "unknown_zone");
+        assert_eq!(lock(&zones[0]).get_patterns(), vec!["a".to_string()]);
+        assert_eq!(lock(&zones[1]).get_patterns(), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn set_correction_merges_with_the_current_values() {
+        let (commands, manager, _) = commands(1);
+        send(&commands, "set_correction", json!({ "gamma": 2.2 }));
+        let reply = send(&commands, "set_correction", json!({ "zone": 0, "scale": { "b": 0.5 } }));
+
+        assert_eq!(reply, json!({ "gamma": 2.2, "scale": { "r": 1.0, "g": 1.0, "b": 0.5, "w": 1.0 } }));
+        assert_eq!(lock(&manager).get_correction().scale.b, 0.5);
+    }
+
+    #[test]
+    fn set_correction_rejects_bad_values() {
+        let (commands, manager, _) = commands(1);
+        let reply = send(&commands, "set_correction", json!({ "scale": { "x": 1.0 } }));
+        assert_eq!(error_kind(&reply), "invalid_args");
+        assert!(reply["error"]["message"].as_str().unwrap().contains("scale.x"));
+
+        assert_eq!(error_kind(&send(&commands, "set_correction", json!({ "scale": 0.5 }))), "invalid_args");
+        assert_eq!(error_kind(&send(&commands, "set_correction", json!({ "gamma": 9.0 }))), "out_of_range");
+        assert_eq!(error_kind(&send(&commands, "set_correction", json!({ "gamma": "high" }))), "invalid_payload");
+        assert_eq!(lock(&manager).get_correction(), Correction::default());
+    }
+
+    #[test]
+    fn set_pattern_params_patches_a_running_pattern() {
+        let (commands, _, capture) = commands(2);
+        send(&commands, "add_pattern", json!({ "name": "dots", "pattern": "fade", "args": { "num_dots": 3 } }));
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "dots", "params": { "color": "#ff0000", "seed": 4 } }));
+
+        assert_eq!(reply, json!({ "tick_rate": 30, "color": "#ff0000", "num_dots": 3, "seed": 4 }));
+        assert!(!capture.is_empty());
+    }
+
+    #[test]
+    fn set_pattern_params_rejects_bad_patches() {
+        let (commands, manager, _) = commands(2);
+        send(&commands, "add_pattern", json!({ "name": "solid", "pattern": "solid", "args": { "color": "red" } }));
+
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "solid", "params": { "colour": "blue" } }));
+        assert_eq!(error_kind(&reply), "invalid_args");
+        assert!(reply["error"]["message"].as_str().unwrap().contains("\"colour\""));
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "solid", "params": ["blue"] }));
+        assert_eq!(error_kind(&reply), "invalid_args");
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "solid", "params": { "color": "nope" } }));
+        assert_eq!(error_kind(&reply), "invalid_args");
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "missing", "params": {} }));
+        assert_eq!(error_kind(&reply), "not_found");
+
+        assert_eq!(lock(&manager).get_pattern_params("solid"), Some(json!({ "color": "#ff0000" })));
+    }
+
+    #[test]
+    fn set_pattern_params_checks_ranges() {
+        let (commands, _, _) = commands(2);
+        send(&commands, "add_pattern", json!({ "name": "crawl", "pattern": "fade_crawl", "args": {} }));
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "crawl", "params": { "tail_len": 0 } }));
+        assert_eq!(error_kind(&reply), "invalid_args");
+        assert!(reply["error"]["message"].as_str().unwrap().contains("tail_len"));
+    }
+
+    #[test]
+    fn solo_and_mute_pick_the_layers_shown() {
+        let (commands, manager, capture) = commands(1);
+        send(&commands, "add_patterns", json!({ "patterns": [
+            { "name": "a", "pattern": "solid", "args": { "color": "#ff0000" } },
+            { "name": "b", "pattern": "solid", "args": { "color": "#0000ff" } },
+        ] }));
+        lock(&manager).advance(1);
+        let frame = || capture.last().unwrap().leds[0];
+        assert_eq!(frame(), Color::rgb(255, 0, 255));
+
+        assert_eq!(send(&commands, "solo_pattern", json!({ "name": "b" })), json!(["a", "b"]));
+        assert_eq!(frame(), Color::rgb(0, 0, 255));
+        send(&commands, "solo_pattern", json!({ "name": "a" }));
+        assert_eq!(frame(), Color::rgb(255, 0, 255));
+        assert_eq!(send(&commands, "unsolo_pattern", json!({ "name": "b" })), json!(["a", "b"]));
+        assert_eq!(frame(), Color::rgb(255, 0, 0));
+        send(&commands, "unsolo_pattern", json!({ "name": "a" }));
+        assert_eq!(frame(), Color::rgb(255, 0, 255));
+
+        // A muted layer stays hidden even while soloed
+        assert_eq!(send(&commands, "mute_pattern", json!({ "name": "a" })), json!(["a", "b"]));
+        assert_eq!(frame(), Color::rgb(0, 0, 255));
+        send(&commands, "solo_pattern", json!({ "name": "a" }));
+        assert_eq!(frame(), Color::BLACK);
+        send(&commands, "unsolo_pattern", json!({ "name": "a" }));
+        assert_eq!(send(&commands, "unmute_pattern", json!({ "name": "a" })), json!(["a", "b"]));
+        assert_eq!(frame(), Color::rgb(255, 0, 255));
+
+        for command in ["solo_pattern", "unsolo_pattern", "mute_pattern", "unmute_pattern"].iter() {
+            assert_eq!(error_kind(&send(&commands, command, json!({ "name": "c" }))), "not_found");
+        }
+    }
+
+    #[test]
+    fn pauses_resumes_and_removes_layers() {
+        let (commands, manager, capture) = commands(1);
+        send(&commands, "add_patterns", json!({ "patterns": [
+            { "name": "a", "pattern": "solid", "args": { "color": "#ff0000" } },
+            { "name": "b", "pattern": "solid", "args": { "color": "#0000ff" } },
+        ] }));
+        lock(&manager).advance(1);
+
+        assert_eq!(send(&commands, "pause_pattern", json!({ "name": "a" })), json!(["a", "b"]));
+        lock(&manager).advance(3);
+        assert_eq!(send(&commands, "get_state", json!({}))["layers"][0]["tick"], json!(1));
+        assert_eq!(send(&commands, "resume_pattern", json!({ "name": "a" })), json!(["a", "b"]));
+        lock(&manager).advance(3);
+        let state = send(&commands, "get_state", json!({}));
+        assert_eq!(state["layers"][0]["tick"], json!(4));
+        assert_eq!(state["layers"][0]["paused"], json!(false));
+
+        assert_eq!(send(&commands, "remove_pattern", json!({ "name": "b" })), json!(["a"]));
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(255, 0, 0)]);
+        for command in ["pause_pattern", "resume_pattern", "remove_pattern"].iter() {
+            assert_eq!(error_kind(&send(&commands, command, json!({ "name": "b" }))), "not_found");
+        }
+    }
+
+    // Panics while the manager is locked, as a bug in a pattern would
+    struct Faulty;
+
+    impl Pattern for Faulty {
+        fn tick_rate(&self) -> u128 { 0 }
+        fn tick_cycle(&self) -> Option<u128> { None }
+        fn tick(&mut self, _tick: u128, _leds: &mut Vec<Color>) -> bool { false }
+        fn set_params(&mut self, _params: Value) -> Result<(), String> {
+            panic!("faulty pattern")
+        }
+    }
+
+    #[test]
+    fn reports_unknown_commands_and_zones() {
+        let (commands, _, _) = commands(1);
+        let reply = send(&commands, "dance", json!({}));
+        assert_eq!(error_kind(&reply), "unknown_command");
+        assert_eq!(reply["error"]["message"], json!("unknown command \"dance\""));
+        let reply = send(&commands, "clear_patterns", json!({ "zone": 3 }));
+        assert_eq!(error_kind(&reply), "unknown_zone");
+        assert_eq!(reply["error"]["message"], json!("unknown zone 3"));
+    }
+
+    #[test]
+    fn keeps_going_after_a_handler_panics() {
+        let (output, capture) = CaptureOutput::new(1);
+        let manager = Arc::new(Mutex::new(PatternManager::new(Box::new(output))));
+        let mut registry = PatternRegistry::default();
+        registry.register("faulty", |_: pattern::SolidArgs| Faulty);
+        let commands = Commands::new(Arc::clone(&manager)).registry(registry);
+
+        send(&commands, "add_pattern", json!({ "name": "faulty", "pattern": "faulty", "args": {} }));
+        let reply = send(&commands, "set_pattern_params", json!({ "name": "faulty", "params": {} }));
+        assert_eq!(error_kind(&reply), "internal");
+        assert_eq!(reply["error"]["message"], json!("internal error: faulty pattern"));
+        assert!(manager.is_poisoned());
+
+        // The poisoned lock doesn't stop later commands from reaching the manager
+        let reply = send(&commands, "add_pattern", json!({ "name": "solid", "pattern": "solid", "args": { "color": "#00ff00" } }));
+        assert_eq!(reply, json!(["faulty", "solid"]));
+        lock(&manager).advance(1);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 255, 0)]);
+    }
+
+    // A few layers using most of what a snapshot records
+    fn build_scene(commands: &Commands, manager: &Mutex<PatternManager>) {
+        send(commands, "set_brightness", json!({ "brightness": 0.5 }));
+        send(commands, "add_patterns", json!({ "patterns": [
+            { "name": "base", "pattern": "gradient", "args": { "start_color": "red", "end_color": "blue" } },
+            { "name": "edge", "pattern": "solid", "args": { "color": "#00ff00" }, "blend": "alpha_over",
+              "opacity": 0.5, "region": { "start": 1, "length": 2, "reverse": true } },
+            { "name": "hidden", "pattern": "fade_crawl", "args": { "tail_len": 3 } },
+        ] }));
+        lock(manager).advance(5);
+        send(commands, "pause_pattern", json!({ "name": "base" }));
+        send(commands, "mute_pattern", json!({ "name": "hidden" }));
+        send(commands, "set_z_index", json!({ "name": "base", "z_index": -1 }));
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let (original, manager, capture) = commands(4);
+        build_scene(&original, &manager);
+        let state = send(&original, "get_state", json!({}));
+        assert_eq!(state["brightness"], json!(0.5));
+        assert_eq!(state["layers"][0]["name"], json!("base"));
+        assert_eq!(state["layers"][0]["tick"], json!(5));
+        assert_eq!(state["layers"][0]["paused"], json!(true));
+
+        let (restored, _, restored_capture) = commands(4);
+        let reply = send(&restored, "set_state", state.clone());
+        assert_eq!(reply, state);
+        assert_eq!(send(&restored, "get_state", json!({})), state);
+        assert_eq!(restored_capture.last().unwrap().leds, capture.last().unwrap().leds);
+    }
+
+    #[test]
+    fn reports_brightness_as_it_was_set() {
+        let (commands, _, _) = commands(1);
+        send(&commands, "set_brightness", json!({ "brightness": 0.8 }));
+        assert_eq!(send(&commands, "get_state", json!({}))["brightness"], json!(0.8));
+
+        let state = json!({ "brightness": 0.3, "layers": [] });
+        assert_eq!(send(&commands, "set_state", state.clone()), state);
+    }
+
+    #[test]
+    fn bad_state_leaves_the_strip_alone() {
+        let (commands, manager, _) = commands(4);
+        build_scene(&commands, &manager);
+        let state = send(&commands, "get_state", json!({}));
+
+        let mut unknown = state.clone();
+        unknown["layers"][1]["pattern"] = json!("sparkles");
+        assert_eq!(error_kind(&send(&commands, "set_state", unknown)), "unknown_pattern");
+        let mut bad_params = state.clone();
+        bad_params["layers"][1]["params"] = json!({ "colour": "red" });
+        assert_eq!(error_kind(&send(&commands, "set_state", bad_params)), "invalid_args");
+        let mut too_bright = state.clone();
+        too_bright["brightness"] = json!(2.0);
+        assert_eq!(error_kind(&send(&commands, "set_state", too_bright)), "out_of_range");
+
+        assert_eq!(send(&commands, "get_state", json!({})), state);
+    }
+}
@@ -0,0 +1,327 @@
+use serde::Deserialize;
+use std::{env, fmt, fs, io};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::correction::Correction;
+use crate::power::PowerModel;
+use crate::white::WhiteMode;
+
+const DEFAULT_PATH: &str = "leds.json";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    InvalidEnv(String, String),
+    // An output was selected that this build was compiled without, with the feature it needs
+    MissingFeature(String),
+    // A setting that parsed but can't be used, with why
+    OutOfRange(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read config: {}", err),
+            ConfigError::Parse(err) => write!(f, "could not parse config: {}", err),
+            ConfigError::InvalidEnv(key, value) => write!(f, "invalid value {:?} for {}", value, key),
+            ConfigError::MissingFeature(feature) => {
+                write!(f, "the {0} output needs the {0:?} feature, which this build doesn't have", feature)
+            }
+            ConfigError::OutOfRange(key, message) => write!(f, "invalid {}: {}", key, message),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub strip: StripConfig,
+    pub client: ClientSettings,
+    pub output: OutputConfig,
+    pub inputs: InputConfig,
+    pub correction: Correction,
+    // Temporal dithering of values that fall between two 8 bit steps, only used by outputs
+    // that support it
+    pub dithering: bool,
+    pub power: PowerModel,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StripConfig {
+    pub pin: i32,
+    pub led_count: i32,
+    pub strip_type: String,
+    pub freq: u32,
+    pub dma: i32,
+    // The second PWM channel, if a second run is wired up
+    pub channel_1: Option<ChannelConfig>,
+    pub layout: Layout,
+    // Only used with RGBW strip types
+    pub white: WhiteMode,
+}
+
+impl Default for StripConfig {
+    fn default() -> StripConfig {
+        StripConfig {
+            pin: 18,
+            led_count: 240,
+            strip_type: default_strip_type(),
+            freq: 800_000,
+            dma: 10,
+            channel_1: None,
+            layout: Layout::Concatenated,
+            white: WhiteMode::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChannelConfig {
+    pub pin: i32,
+    pub led_count: i32,
+    #[serde(default = "default_strip_type")]
+    pub strip_type: String,
+}
+
+fn default_strip_type() -> String { "ws2811_grb".to_string() }
+
+// How the two channels are presented to the patterns: as one long strip running from
+// channel 0 into channel 1, or as separate zones that each get their own patterns.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    Concatenated,
+    Zones,
+}
+
+// Settings for the websocket connection to the relay server
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ClientSettings {
+    pub token: String,
+    pub device: String,
+    pub app: String,
+    pub url: String,
+}
+
+impl Default for ClientSettings {
+    fn default() -> ClientSettings {
+        ClientSettings {
+            token: "test-token".to_string(),
+            device: "test-device".to_string(),
+            app: "room-lights".to_string(),
+            url: "ws://108.174.195.143:8000".to_string(),
+        }
+    }
+}
+
+// Where the rendered frames are sent. Network targets are `host` or `host:port`, with IPv6
+// addresses in brackets when a port is given.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputConfig {
+    Ws281x,
+    E131 {
+        // Multicast when no target is given
+        target: Option<String>,
+        #[serde(default = "default_e131_universe")]
+        universe_start: u16,
+        #[serde(default)]
+        channel_offset: usize,
+    },
+    ArtNet {
+        target: String,
+        #[serde(default)]
+        universe_start: u16,
+        #[serde(default)]
+        sync: bool,
+    },
+    Opc {
+        target: String,
+        #[serde(default)]
+        channel: u8,
+    },
+    Terminal {
+        columns: Option<usize>,
+        #[serde(default)]
+        max_fps: u32,
+    },
+}
+
+// The strip when it can be driven directly, otherwise a preview so a build without
+// ws281x still runs out of the box
+impl Default for OutputConfig {
+    fn default() -> OutputConfig {
+        if cfg!(feature = "ws281x") {
+            OutputConfig::Ws281x
+        } else {
+            OutputConfig::Terminal { columns: None, max_fps: 0 }
+        }
+    }
+}
+
+fn default_e131_universe() -> u16 { 1 }
+
+// Listen addresses for the realtime protocols, each one disabled when unset
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct InputConfig {
+    pub opc_listen: Option<String>,
+    pub ddp_listen: Option<String>,
+    pub wled_listen: Option<String>,
+}
+
+impl Config {
+    // Reads the file at `path`, or at $LEDS_CONFIG, or ./leds.json. A missing default file
+    // just means the defaults are used. LEDS_* environment variables override the file.
+    pub fn load(path: Option<String>) -> Result<Config, ConfigError> {
+        let path = path.or_else(|| env::var("LEDS_CONFIG").ok());
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_PATH).exists() => Config::from_file(DEFAULT_PATH)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.check_features()?;
+        config.check_ranges()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        serde_json::from_str(&contents).map_err(ConfigError::Parse)
+    }
+
+    fn check_features(&self) -> Result<(), ConfigError> {
+        match self.output {
+            OutputConfig::Ws281x if !cfg!(feature = "ws281x") => {
+                Err(ConfigError::MissingFeature("ws281x".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_ranges(&self) -> Result<(), ConfigError> {
+        check_led_count("strip.led_count", self.strip.led_count)?;
+        if let Some(ref second) = self.strip.channel_1 {
+            check_led_count("strip.channel_1.led_count", second.led_count)?;
+        }
+        if let OutputConfig::E131 { channel_offset, .. } = self.output {
+            // A whole pixel has to fit after the offset in a 510 channel universe
+            if channel_offset > 507 {
+                let message = format!("{} leaves no room for a pixel in the first universe", channel_offset);
+                return Err(ConfigError::OutOfRange("output.channel_offset".to_string(), message));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_parsed("LEDS_PIN", &mut self.strip.pin)?;
+        override_parsed("LEDS_LED_COUNT", &mut self.strip.led_count)?;
+        override_parsed("LEDS_STRIP_TYPE", &mut self.strip.strip_type)?;
+        override_parsed("LEDS_FREQ", &mut self.strip.freq)?;
+        override_parsed("LEDS_DMA", &mut self.strip.dma)?;
+
+        override_parsed("LEDS_TOKEN", &mut self.client.token)?;
+        override_parsed("LEDS_DEVICE", &mut self.client.device)?;
+        override_parsed("LEDS_APP", &mut self.client.app)?;
+        override_parsed("LEDS_URL", &mut self.client.url)?;
+
+        override_optional("LEDS_OPC_LISTEN", &mut self.inputs.opc_listen);
+        override_optional("LEDS_DDP_LISTEN", &mut self.inputs.ddp_listen);
+        override_optional("LEDS_WLED_LISTEN", &mut self.inputs.wled_listen);
+        Ok(())
+    }
+}
+
+fn check_led_count(key: &str, led_count: i32) -> Result<(), ConfigError> {
+    if led_count <= 0 {
+        return Err(ConfigError::OutOfRange(key.to_string(), format!("{} isn't a positive number of leds", led_count)));
+    }
+    Ok(())
+}
+
+fn override_parsed<T: FromStr>(key: &str, value: &mut T) -> Result<(), ConfigError> {
+    if let Ok(raw) = env::var(key) {
+        *value = raw.parse().map_err(|_| ConfigError::InvalidEnv(key.to_string(), raw.clone()))?;
+    }
+    Ok(())
+}
+
+fn override_optional(key: &str, value: &mut Option<String>) {
+    if let Ok(raw) = env::var(key) {
+        *value = Some(raw);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_example_config() {
+        let config: Config = serde_json::from_str(include_str!("../leds.example.json")).unwrap();
+        assert_eq!(config.strip.led_count, 240);
+        assert_eq!(config.strip.white, WhiteMode::Min);
+        assert_eq!(config.correction.gamma, 2.2);
+        assert_eq!(config.power.limit_milliamps, Some(10000.0));
+        assert!(!config.dithering);
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let config: Config = serde_json::from_str(r#"{"strip": {"led_count": 10}, "output": {"type": "opc", "target": "host"}}"#).unwrap();
+        assert_eq!(config.strip.led_count, 10);
+        assert_eq!(config.strip.pin, 18);
+        assert!(matches!(config.output, OutputConfig::Opc { channel: 0, .. }));
+    }
+
+    #[test]
+    fn default_output_follows_the_features() {
+        let is_ws281x = matches!(Config::default().output, OutputConfig::Ws281x);
+        assert_eq!(is_ws281x, cfg!(feature = "ws281x"));
+        assert!(Config::default().check_features().is_ok());
+    }
+
+    #[test]
+    fn names_a_missing_feature() {
+        let config = Config { output: OutputConfig::Ws281x, ..Config::default() };
+        let result = config.check_features();
+        assert_eq!(result.is_ok(), cfg!(feature = "ws281x"));
+        if let Err(err) = result {
+            assert!(err.to_string().contains("\"ws281x\" feature"));
+        }
+    }
+
+    #[test]
+    fn rejects_led_counts_below_one() {
+        assert!(Config::default().check_ranges().is_ok());
+        for &led_count in [0, -1].iter() {
+            let mut config = Config::default();
+            config.strip.led_count = led_count;
+            let err = config.check_ranges().unwrap_err();
+            assert!(matches!(err, ConfigError::OutOfRange(ref key, _) if key == "strip.led_count"));
+        }
+
+        let config: Config = serde_json::from_str(r#"{"strip": {"channel_1": {"pin": 13, "led_count": -5}}}"#).unwrap();
+        let err = config.check_ranges().unwrap_err();
+        assert_eq!(err.to_string(), "invalid strip.channel_1.led_count: -5 isn't a positive number of leds");
+    }
+
+    #[test]
+    fn rejects_an_e131_offset_without_room_for_a_pixel() {
+        let output = |channel_offset| OutputConfig::E131 { target: None, universe_start: 1, channel_offset };
+        assert!(Config { output: output(507), ..Config::default() }.check_ranges().is_ok());
+        let err = Config { output: output(510), ..Config::default() }.check_ranges().unwrap_err();
+        assert!(matches!(err, ConfigError::OutOfRange(ref key, _) if key == "output.channel_offset"));
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        assert!(matches!(Config::from_file("does/not/exist.json"), Err(ConfigError::Io(_))));
+    }
+}
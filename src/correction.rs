@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+// Output correction applied to the final frame: a gamma curve so fades and gradients
+// look even to the eye, then per-channel scaling to white balance the strip.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Correction {
+    // 1.0 sends values through unchanged; WS281x strips usually look right around 2.2 to 2.8
+    pub gamma: f64,
+    pub scale: ChannelScale,
+}
+
+impl Default for Correction {
+    fn default() -> Correction {
+        Correction {
+            gamma: 1.0,
+            scale: ChannelScale::default(),
+        }
+    }
+}
+
+// Multipliers from 0 to 1 for each channel at full output
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct ChannelScale {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub w: f64,
+}
+
+impl Default for ChannelScale {
+    fn default() -> ChannelScale {
+        ChannelScale { r: 1.0, g: 1.0, b: 1.0, w: 1.0 }
+    }
+}
+
+impl Correction {
+    pub fn curve(&self) -> CorrectionCurve {
+        let gamma = if self.gamma > 0.0 { self.gamma } else { 1.0 };
+        let scales = [self.scale.r, self.scale.g, self.scale.b, self.scale.w];
+        let mut table = [[0.0; 256]; 4];
+        for (channel, scale) in scales.iter().enumerate() {
+            let scale = scale.clamp(0.0, 1.0);
+            for (value, entry) in table[channel].iter_mut().enumerate() {
+                *entry = ((value as f64 / 255.0).powf(gamma) * scale * 255.0) as f32;
+            }
+        }
+        CorrectionCurve { table }
+    }
+}
+
+// Lookup table built from a Correction. Inputs between two entries are interpolated, so
+// fractional values coming out of the brightness stage keep their precision.
+pub struct CorrectionCurve {
+    table: [[f32; 256]; 4],
+}
+
+impl CorrectionCurve {
+    // Takes and returns [r, g, b, w] in the 0 to 255 range
+    pub fn apply(&self, channels: [f32; 4]) -> [f32; 4] {
+        let mut corrected = [0.0; 4];
+        for channel in 0..4 {
+            let value = channels[channel].clamp(0.0, 255.0);
+            let index = (value as usize).min(254);
+            let frac = value - index as f32;
+            let table = &self.table[channel];
+            corrected[channel] = table[index] + (table[index + 1] - table[index]) * frac;
+        }
+        corrected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 0.01, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn default_passes_values_through() {
+        let curve = Correction::default().curve();
+        assert_close(curve.apply([0.0, 10.25, 127.5, 255.0]), [0.0, 10.25, 127.5, 255.0]);
+    }
+
+    #[test]
+    fn applies_gamma_between_table_entries() {
+        let curve = Correction { gamma: 2.0, ..Correction::default() }.curve();
+        let at = |value: f32| (value / 255.0).powi(2) * 255.0;
+        let [r, g, _, _] = curve.apply([128.0, 127.5, 0.0, 0.0]);
+        assert!((r - at(128.0)).abs() < 0.01);
+        // Halfway between the entries for 127 and 128
+        assert!((g - (at(127.0) + at(128.0)) / 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn scales_each_channel() {
+        let scale = ChannelScale { r: 0.5, g: 1.0, b: 0.0, w: 0.25 };
+        let curve = Correction { gamma: 1.0, scale }.curve();
+        assert_close(curve.apply([255.0; 4]), [127.5, 255.0, 0.0, 63.75]);
+    }
+
+    #[test]
+    fn clamps_out_of_range_settings_and_values() {
+        let scale = ChannelScale { r: 2.0, g: -1.0, b: 1.0, w: 1.0 };
+        let curve = Correction { gamma: 0.0, scale }.curve();
+        assert_close(curve.apply([300.0, 255.0, -5.0, 100.0]), [255.0, 0.0, 0.0, 100.0]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{Mutex, Arc, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::color::Color;
+use crate::pattern::PatternManager;
+
+pub const DDP_PORT: u16 = 4048;
+
+const FLAG_TIMECODE: u8 = 0x10;
+const FLAG_QUERY: u8 = 0x02;
+const FLAG_PUSH: u8 = 0x01;
+const TYPE_RGBW: u8 = 0x18;
+const ID_DISPLAY: u8 = 1;
+const ID_ALL: u8 = 255;
+
+// Receives Distributed Display Protocol packets (as sent by xLights, LedFx and WLED) and
+// shows them in place of the patterns. Data is collected until a packet with the push flag
+// arrives, and the patterns come back once packets stop for `timeout`.
+pub struct DdpServer {
+    socket: UdpSocket,
+    timeout: Duration,
+}
+
+impl DdpServer {
+    pub fn bind(addr: &str) -> io::Result<DdpServer> {
+        Ok(DdpServer {
+            socket: UdpSocket::bind(addr)?,
+            timeout: Duration::from_millis(2_500),
+        })
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> DdpServer {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn start(self, manager: Arc<Mutex<PatternManager>>) {
+        let num_leds = manager.lock().unwrap_or_else(PoisonError::into_inner).num_leds();
+        thread::spawn(move || {
+            let mut frame = vec![Color::BLACK; num_leds];
+            let mut buf = [0u8; 1500];
+            loop {
+                let len = match self.socket.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(err) => {
+                        eprintln!("DDP receive failed: {}", err);
+                        continue;
+                    }
+                };
+                if apply_packet(&buf[..len], &mut frame) {
+                    manager.lock().unwrap_or_else(PoisonError::into_inner).show_external("ddp", &frame, Some(self.timeout));
+                }
+            }
+        });
+    }
+}
+
+// Copies a packet's pixels into the frame, returning whether the frame should be shown
+fn apply_packet(packet: &[u8], frame: &mut [Color]) -> bool {
+    if packet.len() < 10 {
+        return false;
+    }
+    let flags = packet[0];
+    let data_type = packet[2];
+    let id = packet[3];
+    if flags & FLAG_QUERY != 0 || (id != ID_DISPLAY && id != ID_ALL) {
+        return false;
+    }
+
+    let offset = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]) as usize;
+    let len = u16::from_be_bytes([packet[8], packet[9]]) as usize;
+    let header_len = if flags & FLAG_TIMECODE != 0 { 14 } else { 10 };
+    let data = &packet[header_len.min(packet.len())..];
+    let data = &data[..len.min(data.len())];
+
+    let channels = if data_type & 0x38 == TYPE_RGBW { 4 } else { 3 };
+    for (i, &value) in data.iter().enumerate() {
+        let channel = offset + i;
+        let led = channel / channels;
+        if led >= frame.len() {
+            break;
+        }
+        match channel % channels {
+            0 => frame[led].r = value,
+            1 => frame[led].g = value,
+            2 => frame[led].b = value,
+            _ => frame[led].w = value,
+        }
+    }
+
+    flags & FLAG_PUSH != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(flags: u8, data_type: u8, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x40 | flags, 0, data_type, ID_DISPLAY];
+        packet.extend_from_slice(&offset.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn collects_data_until_push() {
+        let mut frame = vec![Color::BLACK; 3];
+        assert!(!apply_packet(&packet(0, 0x0b, 0, &[1, 2, 3, 4]), &mut frame));
+        assert!(apply_packet(&packet(FLAG_PUSH, 0x0b, 4, &[5, 6, 7, 8, 9]), &mut frame));
+        assert_eq!(frame, vec![Color::rgb(1, 2, 3), Color::rgb(4, 5, 6), Color::rgb(7, 8, 9)]);
+    }
+
+    #[test]
+    fn reads_rgbw_and_timecodes() {
+        let mut frame = vec![Color::BLACK; 2];
+        let mut rgbw = packet(FLAG_PUSH | FLAG_TIMECODE, TYPE_RGBW | 0x03, 4, &[]);
+        rgbw.extend_from_slice(&[0, 0, 0, 0]);  // Timecode
+        rgbw.extend_from_slice(&[1, 2, 3, 4]);
+        rgbw[9] = 4;
+        assert!(apply_packet(&rgbw, &mut frame));
+        assert_eq!(frame, vec![Color::BLACK, Color::rgbw(1, 2, 3, 4)]);
+    }
+
+    #[test]
+    fn ignores_malformed_packets() {
+        let mut frame = vec![Color::BLACK; 2];
+        assert!(!apply_packet(&[0x41, 0, 0x0b], &mut frame));
+        assert!(!apply_packet(&packet(FLAG_PUSH | FLAG_QUERY, 0x0b, 0, &[1, 2, 3]), &mut frame));
+        let mut other_id = packet(FLAG_PUSH, 0x0b, 0, &[1, 2, 3]);
+        other_id[3] = 2;
+        assert!(!apply_packet(&other_id, &mut frame));
+        assert_eq!(frame, vec![Color::BLACK; 2]);
+
+        // A timecode flag on a packet too short to hold one
+        let mut truncated = packet(FLAG_PUSH | FLAG_TIMECODE, 0x0b, 0, &[]);
+        truncated.extend_from_slice(&[1, 2]);
+        assert!(apply_packet(&truncated, &mut frame));
+        assert_eq!(frame, vec![Color::BLACK; 2]);
+    }
+
+    #[test]
+    fn clips_data_to_the_frame_and_length() {
+        let mut frame = vec![Color::BLACK; 2];
+        // Says 3 bytes but carries more, then more than the strip has
+        let mut long = packet(FLAG_PUSH, 0x0b, 0, &[1, 2, 3]);
+        long.extend_from_slice(&[9, 9, 9]);
+        assert!(apply_packet(&long, &mut frame));
+        assert!(apply_packet(&packet(FLAG_PUSH, 0x0b, 3, &[4, 5, 6, 7, 8, 9]), &mut frame));
+        assert!(apply_packet(&packet(FLAG_PUSH, 0x0b, u32::MAX, &[1]), &mut frame));
+        assert_eq!(frame, vec![Color::rgb(1, 2, 3), Color::rgb(4, 5, 6)]);
+
+        // Says more than it carries
+        let mut short = packet(FLAG_PUSH, 0x0b, 0, &[7, 7]);
+        short[9] = 200;
+        assert!(apply_packet(&short, &mut frame));
+        assert_eq!(frame[0], Color::rgb(7, 7, 3));
+    }
+}
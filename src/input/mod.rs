@@ -0,0 +1,6 @@
+mod opc;
+pub use self::opc::OpcServer;
+mod ddp;
+pub use self::ddp::{DdpServer, DDP_PORT};
+mod wled;
+pub use self::wled::{WledServer, WLED_PORT};
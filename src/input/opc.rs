@@ -0,0 +1,143 @@
+use std::io::{self, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Mutex, Arc, PoisonError};
+use std::thread;
+
+use crate::color::Color;
+use crate::pattern::PatternManager;
+
+const SET_PIXEL_COLOURS: u8 = 0;
+
+// Accepts Open Pixel Control clients and shows their pixels in place of the patterns until
+// the client disconnects. Every client is a separate source, so clients don't clear each other.
+pub struct OpcServer {
+    listener: TcpListener,
+}
+
+impl OpcServer {
+    pub fn bind(addr: &str) -> io::Result<OpcServer> {
+        Ok(OpcServer {
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+
+    pub fn start(self, manager: Arc<Mutex<PatternManager>>) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("OPC connection failed: {}", err);
+                        continue;
+                    }
+                };
+                let source = match stream.peer_addr() {
+                    Ok(peer) => format!("opc {}", peer),
+                    Err(_) => "opc".to_string(),
+                };
+                let manager = Arc::clone(&manager);
+                thread::spawn(move || {
+                    if let Err(err) = handle_client(stream, &source, &manager) {
+                        eprintln!("OPC client dropped: {}", err);
+                    }
+                    manager.lock().unwrap_or_else(PoisonError::into_inner).release_external(&source);
+                });
+            }
+        });
+    }
+}
+
+fn handle_client(mut stream: TcpStream, source: &str, manager: &Arc<Mutex<PatternManager>>) -> io::Result<()> {
+    let mut header = [0u8; 4];
+    loop {
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let channel = header[0];
+        let command = header[1];
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut data = vec![0u8; len];
+        stream.read_exact(&mut data)?;
+
+        // Channel 0 is a broadcast; the strip itself is channel 1
+        if command != SET_PIXEL_COLOURS || channel > 1 {
+            continue;
+        }
+        let leds: Vec<Color> = data.chunks_exact(3)
+            .map(|rgb| Color::rgb(rgb[0], rgb[1], rgb[2]))
+            .collect();
+        manager.lock().unwrap_or_else(PoisonError::into_inner).show_external(source, &leds, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use crate::output::{CaptureOutput, FrameCapture, Output, OpcOutput};
+    use crate::pattern::SolidPattern;
+
+    // Frames arrive on the server's threads, so give them a moment to show up
+    fn wait_for(capture: &FrameCapture, leds: &[Color]) {
+        let start = Instant::now();
+        while capture.last().map(|frame| frame.leds) != Some(leds.to_vec()) {
+            assert!(start.elapsed() < Duration::from_secs(2), "frame never arrived: {:?}", leds);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn shows_client_frames_until_disconnect() {
+        let (capture_output, capture) = CaptureOutput::new(2);
+        let manager = Arc::new(Mutex::new(PatternManager::new(Box::new(capture_output))));
+        let server = OpcServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.listener.local_addr().unwrap().to_string();
+        server.start(Arc::clone(&manager));
+
+        let mut client = OpcOutput::new(&addr, 2).unwrap().channel(1);
+        client.render(&[Color::rgb(1, 2, 3), Color::rgb(4, 5, 6)]).unwrap();
+        wait_for(&capture, &[Color::rgb(1, 2, 3), Color::rgb(4, 5, 6)]);
+
+        drop(client);
+        wait_for(&capture, &[Color::BLACK, Color::BLACK]);
+    }
+
+    #[test]
+    fn client_frames_bypass_the_patterns() {
+        let (capture_output, capture) = CaptureOutput::new(2);
+        let manager = Arc::new(Mutex::new(PatternManager::new(Box::new(capture_output))));
+        {
+            let mut manager = manager.lock().unwrap();
+            manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(0, 0, 100))));
+            manager.advance(1);
+        }
+        let server = OpcServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.listener.local_addr().unwrap().to_string();
+        server.start(Arc::clone(&manager));
+
+        let mut client = OpcOutput::new(&addr, 2).unwrap().channel(1);
+        client.render(&[Color::BLACK, Color::BLACK]).unwrap();
+        wait_for(&capture, &[Color::BLACK, Color::BLACK]);
+
+        drop(client);
+        wait_for(&capture, &[Color::rgb(0, 0, 100), Color::rgb(0, 0, 100)]);
+    }
+
+    #[test]
+    fn ignores_other_channels() {
+        let (capture_output, capture) = CaptureOutput::new(1);
+        let manager = Arc::new(Mutex::new(PatternManager::new(Box::new(capture_output))));
+        let server = OpcServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.listener.local_addr().unwrap().to_string();
+        server.start(Arc::clone(&manager));
+
+        let mut other = OpcOutput::new(&addr, 1).unwrap().channel(2);
+        other.render(&[Color::rgb(9, 9, 9)]).unwrap();
+        let mut strip = OpcOutput::new(&addr, 1).unwrap().channel(0);
+        strip.render(&[Color::rgb(1, 1, 1)]).unwrap();
+        wait_for(&capture, &[Color::rgb(1, 1, 1)]);
+        assert!(capture.frames().iter().all(|frame| frame.leds != vec![Color::rgb(9, 9, 9)]));
+    }
+}
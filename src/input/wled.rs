@@ -0,0 +1,126 @@
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{Mutex, Arc, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::color::Color;
+use crate::pattern::PatternManager;
+
+pub const WLED_PORT: u16 = 21324;
+
+const WARLS: u8 = 1;
+const DRGB: u8 = 2;
+const DRGBW: u8 = 3;
+const DNRGB: u8 = 4;
+
+// Receives WLED's UDP realtime protocols and shows them in place of the patterns. Every packet
+// carries how many seconds to hold the frame for, with 255 meaning until released.
+pub struct WledServer {
+    socket: UdpSocket,
+}
+
+impl WledServer {
+    pub fn bind(addr: &str) -> io::Result<WledServer> {
+        Ok(WledServer {
+            socket: UdpSocket::bind(addr)?,
+        })
+    }
+
+    pub fn start(self, manager: Arc<Mutex<PatternManager>>) {
+        let num_leds = manager.lock().unwrap_or_else(PoisonError::into_inner).num_leds();
+        thread::spawn(move || {
+            let mut frame = vec![Color::BLACK; num_leds];
+            let mut buf = [0u8; 1500];
+            loop {
+                let len = match self.socket.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(err) => {
+                        eprintln!("WLED receive failed: {}", err);
+                        continue;
+                    }
+                };
+                if len < 2 || !apply_packet(&buf[..len], &mut frame) {
+                    continue;
+                }
+                let timeout = match buf[1] {
+                    255 => None,
+                    seconds => Some(Duration::from_secs(seconds.max(1) as u64)),
+                };
+                manager.lock().unwrap_or_else(PoisonError::into_inner).show_external("wled", &frame, timeout);
+            }
+        });
+    }
+}
+
+// Copies a packet's pixels into the frame, returning false for packets that aren't realtime data
+fn apply_packet(packet: &[u8], frame: &mut [Color]) -> bool {
+    let data = &packet[2..];
+    match packet[0] {
+        WARLS => {
+            for pixel in data.chunks_exact(4) {
+                set_pixel(frame, pixel[0] as usize, Color::rgb(pixel[1], pixel[2], pixel[3]));
+            }
+        }
+        DRGB => {
+            for (i, pixel) in data.chunks_exact(3).enumerate() {
+                set_pixel(frame, i, Color::rgb(pixel[0], pixel[1], pixel[2]));
+            }
+        }
+        DRGBW => {
+            for (i, pixel) in data.chunks_exact(4).enumerate() {
+                set_pixel(frame, i, Color::rgbw(pixel[0], pixel[1], pixel[2], pixel[3]));
+            }
+        }
+        DNRGB => {
+            if data.len() < 2 {
+                return false;
+            }
+            let start = u16::from_be_bytes([data[0], data[1]]) as usize;
+            for (i, pixel) in data[2..].chunks_exact(3).enumerate() {
+                set_pixel(frame, start + i, Color::rgb(pixel[0], pixel[1], pixel[2]));
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn set_pixel(frame: &mut [Color], index: usize, led: Color) {
+    if let Some(pixel) = frame.get_mut(index) {
+        *pixel = led;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_every_realtime_mode() {
+        let mut frame = vec![Color::BLACK; 3];
+        assert!(apply_packet(&[WARLS, 1, 2, 9, 8, 7], &mut frame));
+        assert_eq!(frame[2], Color::rgb(9, 8, 7));
+
+        assert!(apply_packet(&[DRGB, 1, 1, 2, 3, 4, 5, 6], &mut frame));
+        assert_eq!(&frame[..2], &[Color::rgb(1, 2, 3), Color::rgb(4, 5, 6)]);
+
+        assert!(apply_packet(&[DRGBW, 1, 1, 2, 3, 4], &mut frame));
+        assert_eq!(frame[0], Color::rgbw(1, 2, 3, 4));
+
+        assert!(apply_packet(&[DNRGB, 1, 0, 1, 7, 7, 7, 8, 8, 8], &mut frame));
+        assert_eq!(&frame[1..], &[Color::rgb(7, 7, 7), Color::rgb(8, 8, 8)]);
+    }
+
+    #[test]
+    fn ignores_malformed_packets() {
+        let mut frame = vec![Color::BLACK; 2];
+        assert!(!apply_packet(&[0, 1, 1, 2, 3], &mut frame));
+        assert!(!apply_packet(&[DNRGB, 1, 0], &mut frame));
+        // Pixels past the strip and partial pixels are dropped
+        assert!(apply_packet(&[WARLS, 1, 5, 1, 1, 1, 0, 2, 2], &mut frame));
+        assert!(apply_packet(&[DRGB, 1, 3, 3, 3, 4, 4, 4, 5, 5, 5, 6], &mut frame));
+        assert!(apply_packet(&[DNRGB, 1, 255, 255, 1, 2, 3], &mut frame));
+        assert_eq!(frame, vec![Color::rgb(3, 3, 3), Color::rgb(4, 4, 4)]);
+    }
+}
@@ -0,0 +1,13 @@
+pub mod pattern;
+pub mod output;
+pub mod commands;
+pub mod input;
+pub mod config;
+pub mod white;
+pub mod color;
+pub mod correction;
+pub mod power;
+pub mod blend;
+pub mod region;
+pub mod transition;
+pub mod registry;
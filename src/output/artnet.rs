@@ -0,0 +1,158 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::color::Color;
+use super::{resolve, rgb_channels, udp_socket_for, Output, OutputError};
+
+pub const ARTNET_PORT: u16 = 6454;
+
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+const OP_SYNC: u16 = 0x5200;
+const PROTOCOL_VERSION: u16 = 14;
+
+// Sends the strip as ArtDmx packets, one universe per `pixels_per_universe` LEDs so
+// that no pixel is split between universes. With sync enabled an ArtSync follows each
+// frame so nodes latch every universe at once.
+pub struct ArtNetOutput {
+    socket: UdpSocket,
+    target: SocketAddr,
+    num_leds: usize,
+    universe_start: u16,
+    pixels_per_universe: usize,
+    sync: bool,
+    sequence: u8,
+}
+
+impl ArtNetOutput {
+    // `addr` is a node or broadcast address with an optional port
+    pub fn new(addr: &str, num_leds: usize) -> io::Result<ArtNetOutput> {
+        let target = resolve(addr, ARTNET_PORT)?;
+        let socket = udp_socket_for(&target)?;
+        socket.set_broadcast(true)?;
+        Ok(ArtNetOutput {
+            socket,
+            target,
+            num_leds,
+            universe_start: 0,
+            pixels_per_universe: 170,
+            sync: false,
+            sequence: 1,
+        })
+    }
+
+    // 15 bit port address (net, sub-net and universe) of the first universe
+    pub fn universe_start(mut self, universe: u16) -> ArtNetOutput {
+        self.universe_start = universe & 0x7fff;
+        self
+    }
+
+    pub fn pixels_per_universe(mut self, pixels: usize) -> ArtNetOutput {
+        self.pixels_per_universe = pixels.clamp(1, 170);
+        self
+    }
+
+    pub fn sync(mut self, sync: bool) -> ArtNetOutput {
+        self.sync = sync;
+        self
+    }
+
+    fn dmx_packet(&self, universe: u16, data: &[u8]) -> Vec<u8> {
+        // The DMX length has to be even
+        let len = data.len() + data.len() % 2;
+        let mut packet = Vec::with_capacity(18 + len);
+        packet.extend_from_slice(ARTNET_ID);
+        packet.extend_from_slice(&OP_DMX.to_le_bytes());
+        packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        packet.push(self.sequence);
+        packet.push(0);  // Physical port
+        packet.extend_from_slice(&universe.to_le_bytes());  // SubUni then Net
+        packet.extend_from_slice(&(len as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet.resize(18 + len, 0);
+        packet
+    }
+
+    fn sync_packet() -> Vec<u8> {
+        let mut packet = Vec::with_capacity(14);
+        packet.extend_from_slice(ARTNET_ID);
+        packet.extend_from_slice(&OP_SYNC.to_le_bytes());
+        packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);  // Aux1 and Aux2
+        packet
+    }
+}
+
+impl Output for ArtNetOutput {
+    fn num_leds(&self) -> usize { self.num_leds }
+
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        let channels = rgb_channels(leds);
+        for (i, data) in channels.chunks(self.pixels_per_universe * 3).enumerate() {
+            let universe = self.universe_start.wrapping_add(i as u16) & 0x7fff;
+            let packet = self.dmx_packet(universe, data);
+            self.socket.send_to(&packet, self.target)?;
+        }
+        if self.sync {
+            self.socket.send_to(&ArtNetOutput::sync_packet(), self.target)?;
+        }
+
+        // Zero means sequencing is disabled, so it is skipped when wrapping
+        self.sequence = self.sequence.checked_add(1).unwrap_or(1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::testing::{receive, receiver};
+
+    #[test]
+    fn encodes_dmx_packets() {
+        let output = ArtNetOutput::new("127.0.0.1", 1).unwrap();
+        let packet = output.dmx_packet(0x0123, &[1, 2, 3]);
+
+        assert_eq!(&packet[0..8], ARTNET_ID);
+        assert_eq!(&packet[8..10], &[0x00, 0x50]);  // Opcode is little endian
+        assert_eq!(&packet[10..12], &[0, 14]);
+        assert_eq!(packet[12], 1);  // Sequence
+        assert_eq!(packet[13], 0);
+        assert_eq!(&packet[14..16], &[0x23, 0x01]);
+        // Odd lengths are padded to an even one
+        assert_eq!(&packet[16..18], &[0, 4]);
+        assert_eq!(&packet[18..], &[1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn encodes_sync_packets() {
+        assert_eq!(ArtNetOutput::sync_packet(), b"Art-Net\0\x00\x52\x00\x0e\x00\x00".to_vec());
+    }
+
+    #[test]
+    fn keeps_pixels_whole_across_universes() {
+        let (socket, addr) = receiver();
+        let mut output = ArtNetOutput::new(&addr, 3).unwrap()
+            .universe_start(0x7fff)
+            .pixels_per_universe(2)
+            .sync(true);
+        output.render(&[Color::rgb(1, 2, 3), Color::rgb(4, 5, 6), Color::rgb(7, 8, 9)]).unwrap();
+
+        let first = receive(&socket);
+        assert_eq!(&first[14..16], &[0xff, 0x7f]);
+        assert_eq!(&first[18..], &[1, 2, 3, 4, 5, 6]);
+        // The port address wraps within 15 bits
+        let second = receive(&socket);
+        assert_eq!(&second[14..16], &[0, 0]);
+        assert_eq!(&second[18..], &[7, 8, 9, 0]);
+        assert_eq!(&receive(&socket)[8..10], &[0x00, 0x52]);
+    }
+
+    #[test]
+    fn skips_zero_when_the_sequence_wraps() {
+        let mut output = ArtNetOutput::new("127.0.0.1:9", 0).unwrap();
+        output.sequence = 255;
+        output.render(&[]).unwrap();
+        assert_eq!(output.sequence, 1);
+    }
+}
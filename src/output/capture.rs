@@ -0,0 +1,172 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::color::Color;
+use super::{Output, OutputError};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedFrame {
+    pub index: usize,
+    pub timestamp: Duration,  // Time since the capture was created
+    pub leds: Vec<Color>,
+}
+
+// Records every frame instead of displaying it. The output itself is handed to the
+// PatternManager, so frames are read back through the FrameCapture handle.
+pub struct CaptureOutput {
+    num_leds: usize,
+    created: Instant,
+    next_index: usize,
+    limit: Option<usize>,
+    frames: Arc<Mutex<Vec<CapturedFrame>>>,
+}
+
+impl CaptureOutput {
+    pub fn new(num_leds: usize) -> (CaptureOutput, FrameCapture) {
+        CaptureOutput::build(num_leds, None)
+    }
+
+    // Only keeps the most recent `limit` frames, for captures that run indefinitely
+    pub fn with_limit(num_leds: usize, limit: usize) -> (CaptureOutput, FrameCapture) {
+        CaptureOutput::build(num_leds, Some(limit))
+    }
+
+    fn build(num_leds: usize, limit: Option<usize>) -> (CaptureOutput, FrameCapture) {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let output = CaptureOutput {
+            num_leds,
+            created: Instant::now(),
+            next_index: 0,
+            limit,
+            frames: Arc::clone(&frames),
+        };
+        (output, FrameCapture { frames })
+    }
+}
+
+impl Output for CaptureOutput {
+    fn num_leds(&self) -> usize { self.num_leds }
+
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        let frame = CapturedFrame {
+            index: self.next_index,
+            timestamp: self.created.elapsed(),
+            leds: leds.to_vec(),
+        };
+        self.next_index += 1;
+
+        let mut frames = self.frames.lock().unwrap();
+        frames.push(frame);
+        if let Some(limit) = self.limit {
+            if frames.len() > limit {
+                let excess = frames.len() - limit;
+                frames.drain(0..excess);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct FrameCapture {
+    frames: Arc<Mutex<Vec<CapturedFrame>>>,
+}
+
+impl FrameCapture {
+    pub fn len(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn frames(&self) -> Vec<CapturedFrame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    pub fn last(&self) -> Option<CapturedFrame> {
+        self.frames.lock().unwrap().last().cloned()
+    }
+
+    // Looks a frame up by the order it was rendered in, counting from the first render
+    pub fn frame(&self, index: usize) -> Option<CapturedFrame> {
+        self.frames.lock().unwrap().iter()
+            .find(|frame| frame.index == index)
+            .cloned()
+    }
+
+    // The frame that was showing at the given time, i.e. the last one rendered before it
+    pub fn at(&self, timestamp: Duration) -> Option<CapturedFrame> {
+        self.frames.lock().unwrap().iter()
+            .take_while(|frame| frame.timestamp <= timestamp)
+            .last()
+            .cloned()
+    }
+
+    pub fn between(&self, start: Duration, end: Duration) -> Vec<CapturedFrame> {
+        self.frames.lock().unwrap().iter()
+            .filter(|frame| frame.timestamp >= start && frame.timestamp < end)
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.frames.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_frames_in_order() {
+        let (mut output, capture) = CaptureOutput::new(2);
+        output.render(&[Color::rgb(1, 2, 3), Color::BLACK]).unwrap();
+        output.render(&[Color::BLACK, Color::rgbw(4, 5, 6, 7)]).unwrap();
+
+        let frames = capture.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].index, 0);
+        assert_eq!(frames[0].leds, vec![Color::rgb(1, 2, 3), Color::BLACK]);
+        assert_eq!(frames[1].index, 1);
+        assert!(frames[1].timestamp >= frames[0].timestamp);
+        assert_eq!(capture.last(), Some(frames[1].clone()));
+    }
+
+    #[test]
+    fn limit_keeps_the_newest_frames() {
+        let (mut output, capture) = CaptureOutput::with_limit(1, 2);
+        for value in 0..5 {
+            output.render(&[Color::rgb(value, 0, 0)]).unwrap();
+        }
+        assert_eq!(capture.len(), 2);
+        assert_eq!(capture.frame(2), None);
+        assert_eq!(capture.frame(3).unwrap().leds, vec![Color::rgb(3, 0, 0)]);
+        assert_eq!(capture.frame(4).unwrap().leds, vec![Color::rgb(4, 0, 0)]);
+    }
+
+    #[test]
+    fn looks_frames_up_by_time() {
+        let (mut output, capture) = CaptureOutput::new(1);
+        output.render(&[Color::rgb(1, 0, 0)]).unwrap();
+        output.render(&[Color::rgb(2, 0, 0)]).unwrap();
+        let last = capture.last().unwrap();
+
+        assert_eq!(capture.at(last.timestamp).unwrap().index, 1);
+        assert_eq!(capture.between(Duration::from_secs(0), last.timestamp + Duration::from_nanos(1)).len(), 2);
+        capture.clear();
+        assert!(capture.is_empty());
+        assert_eq!(capture.at(last.timestamp), None);
+    }
+
+    #[test]
+    fn uses_the_output_defaults() {
+        let (mut output, _capture) = CaptureOutput::new(3);
+        assert_eq!(output.num_leds(), 3);
+        assert!(!output.has_white());
+        assert!(!output.supports_dithering());
+        assert!(output.flush().is_ok());
+    }
+}
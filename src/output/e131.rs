@@ -0,0 +1,264 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+use rand::Rng;
+
+use crate::color::Color;
+use super::{resolve, rgb_channels, udp_socket_for, Output, OutputError};
+
+pub const E131_PORT: u16 = 5568;
+
+const ACN_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const HEADER_LEN: usize = 126;
+
+enum Destination {
+    Unicast(SocketAddr),
+    Multicast,
+}
+
+// Sends the strip as sACN (E1.31) DMX universes over UDP. The first LED starts at
+// `channel_offset` of `universe_start`, and the rest fill each universe with as many whole
+// pixels as fit in `channels_per_universe` before moving on to the next, so no pixel is
+// split between universes.
+pub struct E131Output {
+    socket: UdpSocket,
+    destination: Destination,
+    num_leds: usize,
+    universe_start: u16,
+    channel_offset: usize,
+    channels_per_universe: usize,
+    priority: u8,
+    source_name: String,
+    cid: [u8; 16],
+    sequence: u8,
+}
+
+impl E131Output {
+    // Sends to a single receiver, `addr` being a host with an optional port
+    pub fn unicast(addr: &str, num_leds: usize) -> io::Result<E131Output> {
+        let target = resolve(addr, E131_PORT)?;
+        E131Output::build(Destination::Unicast(target), num_leds)
+    }
+
+    // Sends every universe to its standard 239.255.x.y multicast group
+    pub fn multicast(num_leds: usize) -> io::Result<E131Output> {
+        E131Output::build(Destination::Multicast, num_leds)
+    }
+
+    fn build(destination: Destination, num_leds: usize) -> io::Result<E131Output> {
+        let socket = match destination {
+            Destination::Unicast(target) => udp_socket_for(&target)?,
+            Destination::Multicast => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.set_multicast_ttl_v4(1)?;
+                socket
+            }
+        };
+        Ok(E131Output {
+            socket,
+            destination,
+            num_leds,
+            universe_start: 1,
+            channel_offset: 0,
+            channels_per_universe: 510,  // 170 whole RGB pixels
+            priority: 100,
+            source_name: "leds".to_string(),
+            cid: rand::thread_rng().gen(),
+            sequence: 0,
+        })
+    }
+
+    pub fn universe_start(mut self, universe: u16) -> E131Output {
+        self.universe_start = universe.max(1);
+        self
+    }
+
+    // Zero based channel within the first universe that the first LED starts at. Kept low
+    // enough that a whole pixel still fits in the first universe.
+    pub fn channel_offset(mut self, offset: usize) -> E131Output {
+        self.channel_offset = offset.min(self.channels_per_universe - 3);
+        self
+    }
+
+    pub fn channels_per_universe(mut self, channels: usize) -> E131Output {
+        self.channels_per_universe = channels.clamp(3, 512);
+        self.channel_offset = self.channel_offset.min(self.channels_per_universe - 3);
+        self
+    }
+
+    // The DMX data of each universe in order, the first one starting with the offset
+    fn universes(&self, leds: &[Color]) -> Vec<Vec<u8>> {
+        let channels = rgb_channels(leds);
+        let first_len = (self.channels_per_universe - self.channel_offset) / 3 * 3;
+        let (first, rest) = channels.split_at(first_len.min(channels.len()));
+
+        let mut data = vec![0; self.channel_offset];
+        data.extend_from_slice(first);
+        let mut universes = vec![data];
+        universes.extend(rest.chunks(self.channels_per_universe / 3 * 3).map(<[u8]>::to_vec));
+        universes
+    }
+
+    pub fn priority(mut self, priority: u8) -> E131Output {
+        self.priority = priority.min(200);
+        self
+    }
+
+    pub fn source_name(mut self, name: &str) -> E131Output {
+        self.source_name = name.to_string();
+        self
+    }
+
+    fn target(&self, universe: u16) -> SocketAddr {
+        match self.destination {
+            Destination::Unicast(addr) => addr,
+            Destination::Multicast => {
+                let group = Ipv4Addr::new(239, 255, (universe >> 8) as u8, universe as u8);
+                SocketAddr::from((group, E131_PORT))
+            }
+        }
+    }
+
+    fn packet(&self, universe: u16, data: &[u8]) -> Vec<u8> {
+        let len = HEADER_LEN + data.len();
+        let mut packet = Vec::with_capacity(len);
+
+        // Root layer
+        packet.extend_from_slice(&0x0010u16.to_be_bytes());
+        packet.extend_from_slice(&0x0000u16.to_be_bytes());
+        packet.extend_from_slice(ACN_IDENTIFIER);
+        packet.extend_from_slice(&flags_and_length(len - 16));
+        packet.extend_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet.extend_from_slice(&self.cid);
+
+        // Framing layer
+        packet.extend_from_slice(&flags_and_length(len - 38));
+        packet.extend_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        let mut source_name = [0u8; 64];
+        let name = self.source_name.as_bytes();
+        let name_len = name.len().min(63);
+        source_name[..name_len].copy_from_slice(&name[..name_len]);
+        packet.extend_from_slice(&source_name);
+        packet.push(self.priority);
+        packet.extend_from_slice(&0u16.to_be_bytes());  // Synchronization address
+        packet.push(self.sequence);
+        packet.push(0);  // Options
+        packet.extend_from_slice(&universe.to_be_bytes());
+
+        // DMP layer
+        packet.extend_from_slice(&flags_and_length(len - 115));
+        packet.push(VECTOR_DMP_SET_PROPERTY);
+        packet.push(0xa1);  // Address and data type
+        packet.extend_from_slice(&0u16.to_be_bytes());  // First property address
+        packet.extend_from_slice(&1u16.to_be_bytes());  // Address increment
+        packet.extend_from_slice(&((data.len() + 1) as u16).to_be_bytes());
+        packet.push(0);  // DMX start code
+        packet.extend_from_slice(data);
+
+        packet
+    }
+}
+
+impl Output for E131Output {
+    fn num_leds(&self) -> usize { self.num_leds }
+
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        for (i, data) in self.universes(leds).iter().enumerate() {
+            let universe = self.universe_start.wrapping_add(i as u16);
+            let packet = self.packet(universe, data);
+            self.socket.send_to(&packet, self.target(universe))?;
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
+}
+
+fn flags_and_length(len: usize) -> [u8; 2] {
+    (0x7000 | (len as u16 & 0x0fff)).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::testing::{receive, receiver};
+
+    #[test]
+    fn encodes_the_layers() {
+        let output = E131Output::unicast("127.0.0.1", 1).unwrap().priority(150).source_name("strip");
+        let packet = output.packet(7, &[1, 2, 3]);
+
+        assert_eq!(packet.len(), HEADER_LEN + 3);
+        assert_eq!(&packet[0..4], &[0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(&packet[4..16], ACN_IDENTIFIER);
+        assert_eq!(&packet[16..18], &[0x70, 113]);  // 129 bytes after the preamble
+        assert_eq!(&packet[18..22], &[0, 0, 0, 4]);
+        assert_eq!(&packet[22..38], &output.cid);
+
+        assert_eq!(&packet[38..40], &[0x70, 91]);
+        assert_eq!(&packet[40..44], &[0, 0, 0, 2]);
+        assert_eq!(&packet[44..50], b"strip\0");
+        assert_eq!(packet[108], 150);
+        assert_eq!(packet[111], 0);  // Sequence
+        assert_eq!(&packet[113..115], &[0, 7]);
+
+        assert_eq!(&packet[115..117], &[0x70, 14]);
+        assert_eq!(&packet[117..119], &[0x02, 0xa1]);
+        assert_eq!(&packet[119..125], &[0, 0, 0, 1, 0, 4]);
+        assert_eq!(packet[125], 0);  // Start code
+        assert_eq!(&packet[126..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn spills_whole_pixels_into_the_next_universe() {
+        let (socket, addr) = receiver();
+        let mut output = E131Output::unicast(&addr, 3).unwrap()
+            .universe_start(4)
+            .channel_offset(2)
+            .channels_per_universe(6);
+        output.render(&[Color::rgb(1, 2, 3), Color::rgb(4, 5, 6), Color::rgbw(7, 8, 9, 1)]).unwrap();
+
+        // The offset leaves room for one pixel, which doesn't get split to fill the rest
+        let first = receive(&socket);
+        assert_eq!(&first[113..115], &[0, 4]);
+        assert_eq!(&first[123..125], &[0, 6]);
+        assert_eq!(&first[126..], &[0, 0, 1, 2, 3]);
+
+        // White is folded into the RGB channels
+        let second = receive(&socket);
+        assert_eq!(&second[113..115], &[0, 5]);
+        assert_eq!(&second[126..], &[4, 5, 6, 8, 9, 10]);
+    }
+
+    #[test]
+    fn keeps_a_pixel_in_the_first_universe() {
+        let output = E131Output::multicast(2).unwrap().channel_offset(600);
+        let universes = output.universes(&[Color::rgb(1, 2, 3), Color::rgb(4, 5, 6)]);
+        assert_eq!(universes.len(), 2);
+        assert_eq!(universes[0].len(), 510);
+        assert_eq!(&universes[0][507..], &[1, 2, 3]);
+        assert_eq!(universes[1], vec![4, 5, 6]);
+
+        // Shrinking the universe afterwards pulls the offset in with it
+        let output = output.channels_per_universe(9);
+        assert_eq!(output.universes(&[Color::rgb(1, 2, 3)]), vec![vec![0, 0, 0, 0, 0, 0, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn counts_the_sequence_per_frame() {
+        let (socket, addr) = receiver();
+        let mut output = E131Output::unicast(&addr, 1).unwrap();
+        output.render(&[Color::BLACK]).unwrap();
+        output.render(&[Color::BLACK]).unwrap();
+        assert_eq!(receive(&socket)[111], 0);
+        assert_eq!(receive(&socket)[111], 1);
+    }
+
+    #[test]
+    fn multicasts_to_the_universe_group() {
+        let output = E131Output::multicast(1).unwrap();
+        assert_eq!(output.target(0x0102), "239.255.1.2:5568".parse().unwrap());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt, io};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::color::Color;
+use crate::config::{Config, OutputConfig};
+
+mod capture;
+pub use self::capture::{CaptureOutput, CapturedFrame, FrameCapture};
+mod terminal;
+pub use self::terminal::TerminalOutput;
+mod e131;
+pub use self::e131::E131Output;
+mod artnet;
+pub use self::artnet::ArtNetOutput;
+mod opc;
+pub use self::opc::OpcOutput;
+
+#[cfg(feature = "ws281x")]
+mod ws281x;
+#[cfg(feature = "ws281x")]
+pub use self::ws281x::Ws281xOutput;
+
+#[derive(Debug)]
+pub enum OutputError {
+    Io(io::Error),
+    Device(String),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::Io(err) => write!(f, "io error: {}", err),
+            OutputError::Device(message) => write!(f, "device error: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for OutputError {
+    fn from(err: io::Error) -> OutputError {
+        OutputError::Io(err)
+    }
+}
+
+// Anything the composited strip can be rendered into. Each output is responsible for
+// putting the channels in the order its hardware or protocol expects.
+pub trait Output: Send {
+    fn num_leds(&self) -> usize;
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError>;
+    // Whether the target has a dedicated white die. Outputs without one fold the white
+    // channel back into r, g and b themselves.
+    fn has_white(&self) -> bool { false }
+    // Whether the manager may dither for this output. Dithering keeps re-sending frames every
+    // few milliseconds, which only pays off on a strip driven directly; network and preview
+    // outputs would just be flooded.
+    fn supports_dithering(&self) -> bool { false }
+    // Called on every manager tick so an output that held a frame back, e.g. to stay under a
+    // frame rate, can send it once it is allowed to
+    fn flush(&mut self) -> Result<(), OutputError> { Ok(()) }
+}
+
+// Builds the outputs selected in the config, one for each zone
+pub fn from_config(config: &Config) -> Result<Vec<Box<dyn Output>>, OutputError> {
+    let num_leds = config.strip.led_count as usize;
+    let output: Box<dyn Output> = match config.output {
+        #[cfg(feature = "ws281x")]
+        OutputConfig::Ws281x if config.strip.layout == crate::config::Layout::Zones => {
+            return Ok(Ws281xOutput::zones(&config.strip)?.into_iter()
+                .map(|output| Box::new(output) as Box<dyn Output>)
+                .collect());
+        }
+        #[cfg(feature = "ws281x")]
+        OutputConfig::Ws281x => Box::new(Ws281xOutput::new(&config.strip)?),
+        #[cfg(not(feature = "ws281x"))]
+        OutputConfig::Ws281x => {
+            return Err(OutputError::Device("built without the \"ws281x\" feature".to_string()));
+        }
+        OutputConfig::E131 { ref target, universe_start, channel_offset } => {
+            let output = match target {
+                Some(target) => E131Output::unicast(target, num_leds)?,
+                None => E131Output::multicast(num_leds)?,
+            };
+            Box::new(output.universe_start(universe_start).channel_offset(channel_offset))
+        }
+        OutputConfig::ArtNet { ref target, universe_start, sync } => {
+            Box::new(ArtNetOutput::new(target, num_leds)?.universe_start(universe_start).sync(sync))
+        }
+        OutputConfig::Opc { ref target, channel } => {
+            Box::new(OpcOutput::new(target, num_leds)?.channel(channel))
+        }
+        OutputConfig::Terminal { columns, max_fps } => {
+            let mut output = TerminalOutput::stdout(num_leds).max_fps(max_fps);
+            if let Some(columns) = columns {
+                output = output.columns(columns);
+            }
+            Box::new(output)
+        }
+    };
+    Ok(vec![output])
+}
+
+// Resolves a network target given as `host` or `host:port`, falling back to `default_port`.
+// IPv6 literals need brackets to carry a port, e.g. `::1` or `[::1]:7890`.
+pub(crate) fn resolve(addr: &str, default_port: u16) -> io::Result<SocketAddr> {
+    if let Ok(target) = addr.parse::<SocketAddr>() {
+        return Ok(target);
+    }
+    let host = addr.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(addr);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    let target = match host.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid port in {}", addr)))?;
+            (host, port).to_socket_addrs()?.next()
+        }
+        None => (host, default_port).to_socket_addrs()?.next(),
+    };
+    target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve {}", addr)))
+}
+
+// A socket to send datagrams from, of the same address family as `target`
+pub(crate) fn udp_socket_for(target: &SocketAddr) -> io::Result<UdpSocket> {
+    if target.is_ipv6() {
+        UdpSocket::bind("[::]:0")
+    } else {
+        UdpSocket::bind("0.0.0.0:0")
+    }
+}
+
+// Flattens a frame into r, g, b bytes for targets without a white channel
+pub(crate) fn rgb_channels(leds: &[Color]) -> Vec<u8> {
+    let mut channels = Vec::with_capacity(leds.len() * 3);
+    for led in leds {
+        channels.push(led.r.saturating_add(led.w));
+        channels.push(led.g.saturating_add(led.w));
+        channels.push(led.b.saturating_add(led.w));
+    }
+    channels
+}
+
+// Helpers for the tests of the network outputs
+#[cfg(test)]
+mod testing {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    // A local socket to send to, with its address
+    pub fn receiver() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        (socket, addr)
+    }
+
+    pub fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_hosts_with_and_without_ports() {
+        assert_eq!(resolve("127.0.0.1", 5568).unwrap(), "127.0.0.1:5568".parse().unwrap());
+        assert_eq!(resolve("127.0.0.1:9", 5568).unwrap(), "127.0.0.1:9".parse().unwrap());
+        assert_eq!(resolve("localhost:9", 5568).unwrap().port(), 9);
+        assert_eq!(resolve("localhost", 5568).unwrap().port(), 5568);
+        assert!(resolve("localhost:port", 5568).is_err());
+    }
+
+    #[test]
+    fn resolves_ipv6_literals() {
+        assert_eq!(resolve("::1", 7890).unwrap(), "[::1]:7890".parse().unwrap());
+        assert_eq!(resolve("[::1]", 7890).unwrap(), "[::1]:7890".parse().unwrap());
+        assert_eq!(resolve("[::1]:9", 7890).unwrap(), "[::1]:9".parse().unwrap());
+        assert_eq!(resolve("fe80::1:2", 7890).unwrap(), "[fe80::1:2]:7890".parse().unwrap());
+    }
+}
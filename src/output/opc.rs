@@ -0,0 +1,134 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::color::Color;
+use super::{resolve, rgb_channels, Output, OutputError};
+
+pub const OPC_PORT: u16 = 7890;
+
+const SET_PIXEL_COLOURS: u8 = 0;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// Frames are sent while the manager is locked, so a server that stops reading mustn't
+// block the render loop for longer than this
+const WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+
+// Streams frames to an Open Pixel Control server such as fadecandy's fcserver. The
+// connection is made lazily and re-established if the server goes away or stops keeping up.
+pub struct OpcOutput {
+    target: SocketAddr,
+    channel: u8,
+    num_leds: usize,
+    stream: Option<TcpStream>,
+    last_attempt: Option<Instant>,
+}
+
+impl OpcOutput {
+    // `addr` is a host with an optional port
+    pub fn new(addr: &str, num_leds: usize) -> io::Result<OpcOutput> {
+        let target = resolve(addr, OPC_PORT)?;
+        Ok(OpcOutput {
+            target,
+            channel: 0,
+            num_leds,
+            stream: None,
+            last_attempt: None,
+        })
+    }
+
+    // Zero broadcasts to every channel on the server
+    pub fn channel(mut self, channel: u8) -> OpcOutput {
+        self.channel = channel;
+        self
+    }
+
+    fn connect(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            if let Some(last) = self.last_attempt {
+                // Don't hold up the render loop retrying a server that is down
+                if last.elapsed() < RECONNECT_INTERVAL {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, "Waiting to reconnect to OPC server"));
+                }
+            }
+            self.last_attempt = Some(Instant::now());
+            let stream = TcpStream::connect_timeout(&self.target, Duration::from_millis(500))?;
+            stream.set_nodelay(true)?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+}
+
+impl Output for OpcOutput {
+    fn num_leds(&self) -> usize { self.num_leds }
+
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        let data = rgb_channels(leds);
+        let mut message = Vec::with_capacity(4 + data.len());
+        message.push(self.channel);
+        message.push(SET_PIXEL_COLOURS);
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&data);
+
+        let result = self.connect().and_then(|stream| stream.write_all(&message));
+        if result.is_err() {
+            // A timed out write may have sent part of the message, so the stream can't be reused
+            self.stream = None;
+        }
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn sends_set_pixel_colours() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut output = OpcOutput::new(&addr, 2).unwrap().channel(3);
+        output.render(&[Color::rgb(1, 2, 3), Color::rgbw(4, 5, 6, 1)]).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut message = [0u8; 10];
+        stream.read_exact(&mut message).unwrap();
+        assert_eq!(message, [3, 0, 0, 6, 1, 2, 3, 5, 6, 7]);
+    }
+
+    #[test]
+    fn waits_before_reconnecting() {
+        // Bind and drop a listener to find a port nothing is listening on
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut output = OpcOutput::new(&addr, 1).unwrap();
+
+        assert!(output.render(&[Color::BLACK]).is_err());
+        match output.render(&[Color::BLACK]) {
+            Err(OutputError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotConnected),
+            _ => panic!("expected to wait before reconnecting"),
+        }
+    }
+
+    #[test]
+    fn drops_a_server_that_stops_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut output = OpcOutput::new(&addr, 20_000).unwrap();
+        let frame = vec![Color::rgb(1, 2, 3); 20_000];
+        output.render(&frame).unwrap();
+        let (_stream, _) = listener.accept().unwrap();
+
+        // Once the socket buffers are full the write gives up instead of blocking
+        let start = Instant::now();
+        while output.render(&frame).is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(10), "write never timed out");
+        }
+        match output.render(&frame) {
+            Err(OutputError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotConnected),
+            _ => panic!("expected the stream to be dropped"),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::env;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::color::Color;
+use super::{rgb_channels, Output, OutputError};
+
+// Previews the strip as rows of 24-bit colour blocks, redrawn in place on every render.
+pub struct TerminalOutput {
+    num_leds: usize,
+    columns: usize,
+    writer: Box<dyn Write + Send>,
+    min_interval: Option<Duration>,
+    last_render: Option<Instant>,
+    // The newest frame that arrived too soon after the last one, drawn by flush
+    pending: Option<Vec<Color>>,
+    rows_drawn: usize,
+}
+
+impl TerminalOutput {
+    pub fn new(num_leds: usize, writer: Box<dyn Write + Send>) -> TerminalOutput {
+        // Each LED is drawn two cells wide so they come out roughly square
+        let width = env::var("COLUMNS").ok()
+            .and_then(|columns| columns.parse::<usize>().ok())
+            .unwrap_or(80);
+        TerminalOutput {
+            num_leds,
+            columns: (width / 2).max(1),
+            writer,
+            min_interval: None,
+            last_render: None,
+            pending: None,
+            rows_drawn: 0,
+        }
+    }
+
+    pub fn stdout(num_leds: usize) -> TerminalOutput {
+        TerminalOutput::new(num_leds, Box::new(io::stdout()))
+    }
+
+    pub fn stderr(num_leds: usize) -> TerminalOutput {
+        TerminalOutput::new(num_leds, Box::new(io::stderr()))
+    }
+
+    // Number of LEDs drawn per row before wrapping
+    pub fn columns(mut self, columns: usize) -> TerminalOutput {
+        self.columns = columns.max(1);
+        self
+    }
+
+    // Holds back frames that arrive faster than this, which keeps slow links like SSH usable.
+    // Only the newest held frame is kept and it is drawn once the interval is up.
+    pub fn max_fps(mut self, fps: u32) -> TerminalOutput {
+        self.min_interval = if fps > 0 {
+            Some(Duration::from_micros(1_000_000 / fps as u64))
+        } else {
+            None
+        };
+        self
+    }
+}
+
+impl Output for TerminalOutput {
+    fn num_leds(&self) -> usize { self.num_leds }
+
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        if self.too_soon() {
+            self.pending = Some(leds.to_vec());
+            return Ok(());
+        }
+        self.pending = None;
+        self.draw(leds)
+    }
+
+    fn flush(&mut self) -> Result<(), OutputError> {
+        if self.pending.is_none() || self.too_soon() {
+            return Ok(());
+        }
+        let leds = self.pending.take().unwrap_or_default();
+        self.draw(&leds)
+    }
+}
+
+impl TerminalOutput {
+    fn too_soon(&self) -> bool {
+        match (self.min_interval, self.last_render) {
+            (Some(interval), Some(last)) => last.elapsed() < interval,
+            _ => false,
+        }
+    }
+
+    fn draw(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        self.last_render = Some(Instant::now());
+
+        let mut out = String::new();
+        if self.rows_drawn > 0 {
+            // Jump back to the start of the previous preview so it is overwritten
+            out.push_str(&format!("\x1b[{}A\r", self.rows_drawn));
+        }
+        let mut rows = 0;
+        for row in rgb_channels(leds).chunks(self.columns * 3) {
+            for rgb in row.chunks(3) {
+                out.push_str(&format!("\x1b[48;2;{};{};{}m  ", rgb[0], rgb[1], rgb[2]));
+            }
+            out.push_str("\x1b[0m\x1b[K\n");
+            rows += 1;
+        }
+        self.rows_drawn = rows;
+
+        self.writer.write_all(out.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Shared {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().split_off(0)).unwrap()
+        }
+    }
+
+    #[test]
+    fn draws_rows_in_place() {
+        let shared = Shared::default();
+        let mut output = TerminalOutput::new(3, Box::new(shared.clone())).columns(2);
+        output.render(&[Color::rgb(1, 2, 3), Color::rgbw(0, 0, 0, 9), Color::BLACK]).unwrap();
+        assert_eq!(
+            shared.take(),
+            "\x1b[48;2;1;2;3m  \x1b[48;2;9;9;9m  \x1b[0m\x1b[K\n\x1b[48;2;0;0;0m  \x1b[0m\x1b[K\n",
+        );
+
+        output.render(&[Color::BLACK; 3]).unwrap();
+        assert!(shared.take().starts_with("\x1b[2A\r"));
+    }
+
+    #[test]
+    fn holds_back_frames_over_the_rate_and_draws_the_latest() {
+        let shared = Shared::default();
+        let mut output = TerminalOutput::new(1, Box::new(shared.clone())).max_fps(20);
+        output.render(&[Color::rgb(1, 0, 0)]).unwrap();
+        shared.take();
+
+        output.render(&[Color::rgb(2, 0, 0)]).unwrap();
+        output.render(&[Color::rgb(3, 0, 0)]).unwrap();
+        output.flush().unwrap();
+        assert_eq!(shared.take(), "");
+
+        thread::sleep(Duration::from_millis(60));
+        output.flush().unwrap();
+        let drawn = shared.take();
+        assert!(drawn.contains("48;2;3;0;0m") && !drawn.contains("48;2;2;0;0m"));
+        output.flush().unwrap();
+        assert_eq!(shared.take(), "");
+    }
+}
@@ -0,0 +1,139 @@
+use rs_ws281x::{ChannelBuilder, StripType, ControllerBuilder, Controller};
+use std::sync::{Mutex, Arc};
+
+use crate::color::Color;
+use super::{Output, OutputError};
+use crate::config::StripConfig;
+
+type SharedController = Arc<Mutex<Controller>>;
+
+// Drives one or both of the PWM channels. Outputs built from the same config share a
+// controller, so each zone can render its own channel independently.
+pub struct Ws281xOutput {
+    controller: SharedController,
+    channels: Vec<(usize, usize)>,  // (channel, led count) in the order they are filled
+    num_leds: usize,
+    has_white: bool,
+}
+
+impl Ws281xOutput {
+    // Treats every configured channel as one strip, channel 0 first
+    pub fn new(strip: &StripConfig) -> Result<Ws281xOutput, OutputError> {
+        let (controller, channels) = build_controller(strip)?;
+        let has_white = channels.iter().all(|&(channel, _)| channel_has_white(strip, channel));
+        Ok(Ws281xOutput::for_channels(controller, channels, has_white))
+    }
+
+    // One output per configured channel
+    pub fn zones(strip: &StripConfig) -> Result<Vec<Ws281xOutput>, OutputError> {
+        let (controller, channels) = build_controller(strip)?;
+        Ok(channels.into_iter()
+            .map(|channel| {
+                let has_white = channel_has_white(strip, channel.0);
+                Ws281xOutput::for_channels(Arc::clone(&controller), vec![channel], has_white)
+            })
+            .collect())
+    }
+
+    fn for_channels(controller: SharedController, channels: Vec<(usize, usize)>, has_white: bool) -> Ws281xOutput {
+        Ws281xOutput {
+            num_leds: channels.iter().map(|&(_, count)| count).sum(),
+            controller,
+            channels,
+            has_white,
+        }
+    }
+}
+
+impl Output for Ws281xOutput {
+    fn num_leds(&self) -> usize { self.num_leds }
+
+    fn has_white(&self) -> bool { self.has_white }
+
+    fn supports_dithering(&self) -> bool { true }
+
+    fn render(&mut self, leds: &[Color]) -> Result<(), OutputError> {
+        let mut controller = self.controller.lock().unwrap();
+        let mut start = 0;
+        for &(channel, count) in self.channels.iter() {
+            let end = (start + count).min(leds.len());
+            let strip = controller.leds_mut(channel);
+            for (i, led) in leds[start.min(end)..end].iter().enumerate().take(strip.len()) {
+                // The driver takes [b, g, r, w] and reorders for the strip type itself
+                strip[i] = if self.has_white {
+                    [led.b, led.g, led.r, led.w]
+                } else {
+                    [led.b.saturating_add(led.w), led.g.saturating_add(led.w), led.r.saturating_add(led.w), 0]
+                };
+            }
+            start += count;
+        }
+
+        controller.render()
+            .map_err(|err| OutputError::Device(format!("{:?}", err)))
+    }
+}
+
+fn build_controller(strip: &StripConfig) -> Result<(SharedController, Vec<(usize, usize)>), OutputError> {
+    let mut channels = vec![(0, strip.led_count as usize)];
+    let mut builder = ControllerBuilder::new();
+    builder
+        .freq(strip.freq)
+        .dma(strip.dma)
+        .channel(0, build_channel(strip.pin, strip.led_count, &strip.strip_type)?);
+
+    if let Some(ref second) = strip.channel_1 {
+        builder.channel(1, build_channel(second.pin, second.led_count, &second.strip_type)?);
+        channels.push((1, second.led_count as usize));
+    }
+
+    let controller = builder
+        .build()
+        .map_err(|err| OutputError::Device(format!("Failed to create controller: {:?}", err)))?;
+    Ok((Arc::new(Mutex::new(controller)), channels))
+}
+
+fn build_channel(pin: i32, led_count: i32, strip_type: &str) -> Result<rs_ws281x::Channel, OutputError> {
+    let strip_type = parse_strip_type(strip_type)
+        .ok_or_else(|| OutputError::Device(format!("Unknown strip type: {}", strip_type)))?;
+
+    Ok(ChannelBuilder::new()
+        .pin(pin)
+        .count(led_count)
+        .strip_type(strip_type)
+        .brightness(255)
+        .build())
+}
+
+fn parse_strip_type(name: &str) -> Option<StripType> {
+    let strip_type = match name {
+        "ws2811_rgb" => StripType::Ws2811Rgb,
+        "ws2811_rbg" => StripType::Ws2811Rbg,
+        "ws2811_grb" => StripType::Ws2811Grb,
+        "ws2811_gbr" => StripType::Ws2811Gbr,
+        "ws2811_brg" => StripType::Ws2811Brg,
+        "ws2811_bgr" => StripType::Ws2811Bgr,
+        "ws2812" => StripType::Ws2812,
+        "sk6812" => StripType::Sk6812,
+        "sk6812w" => StripType::Sk6812W,
+        "sk6812_rgbw" => StripType::Sk6812Rgbw,
+        "sk6812_rbgw" => StripType::Sk6812Rbgw,
+        "sk6812_gbrw" => StripType::Sk6812Gbrw,
+        "sk6812_grbw" => StripType::Sk6812Grbw,
+        "sk6812_brgw" => StripType::Sk6812Brgw,
+        "sk6812_bgrw" => StripType::Sk6812Bgrw,
+        _ => return None,
+    };
+    Some(strip_type)
+}
+
+fn channel_has_white(strip: &StripConfig, channel: usize) -> bool {
+    let strip_type = match channel {
+        0 => &strip.strip_type,
+        _ => match strip.channel_1 {
+            Some(ref second) => &second.strip_type,
+            None => return false,
+        },
+    };
+    strip_type.starts_with("sk6812") && strip_type.ends_with('w')
+}
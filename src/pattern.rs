@@ -1,13 +1,23 @@
-use::rs_ws281x::{ChannelBuilder, StripType, ControllerBuilder, Controller};
 use::std::{time, thread};
-use::std::sync::{Mutex, Arc};
+use::std::sync::{Mutex, Arc, PoisonError, TryLockError};
 use hsl::HSL;
-use std::collections::HashMap;
 
 extern crate rand;
 use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
 use std::time::SystemTime;
 
+use crate::blend::{self, BlendMode};
+use crate::color::Color;
+use crate::correction::{Correction, CorrectionCurve};
+use crate::output::Output;
+use crate::power::{PowerBudget, PowerModel, PowerReport, PowerShare};
+use crate::region::Region;
+use crate::transition::{Transition, TransitionStyle};
+use crate::white::WhiteMode;
+
 pub struct MovingRainbow {
     pub tick_rate: u128,
     pub tick_cycle: Option<u128>,
@@ -34,17 +44,52 @@ impl MovingRainbow {
     }
 }
 
+// Args of both rainbow patterns
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RainbowArgs { pub tick_rate: u128, pub saturation: f64, pub lightness: f64, pub brightness: f64 }
+
+impl Default for RainbowArgs {
+    fn default() -> RainbowArgs {
+        RainbowArgs { tick_rate: 30, saturation: 0.7, lightness: 0.3, brightness: 0.3 }
+    }
+}
+
+impl PatternArgs for RainbowArgs {
+    fn describe() -> Vec<ParamSchema> {
+        vec![
+            tick_rate_schema("How fast the hue moves, one degree per tick"),
+            ParamSchema::new("saturation", ParamType::Number, "Colour saturation").range(0.0, 1.0),
+            ParamSchema::new("lightness", ParamType::Number, "Colour lightness").range(0.0, 1.0),
+            ParamSchema::new("brightness", ParamType::Number, "Scale applied to the colour").range(0.0, 1.0),
+        ]
+    }
+}
+
 impl Pattern for MovingRainbow {
     fn tick_rate(&self) -> u128 { self.tick_rate }
     fn tick_cycle(&self) -> Option<u128> { self.tick_cycle }
-    fn tick(&mut self, tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn params(&self) -> Value {
+        json!(RainbowArgs { tick_rate: self.tick_rate, saturation: self.saturation, lightness: self.lightness, brightness: self.brightness })
+    }
+    fn set_params(&mut self, params: Value) -> Result<(), String> {
+        let args: RainbowArgs = parse_args(params)?;
+        self.tick_rate = args.tick_rate;
+        self.saturation = args.saturation;
+        self.lightness = args.lightness;
+        self.brightness = args.brightness;
+        self.color.s = args.saturation;
+        self.color.l = args.lightness;
+        Ok(())
+    }
+    fn tick(&mut self, tick: u128, leds: &mut Vec<Color>) -> bool {
         for i in 0..leds.len() {
             self.color.h = ( (tick as usize + i) % leds.len() * 360 / leds.len() ) as f64;
             let rgb = self.color.to_rgb();
             let r_val = (rgb.0 as f64 * self.brightness) as u8;
             let g_val = (rgb.1 as f64 * self.brightness) as u8;
             let b_val = (rgb.2 as f64 * self.brightness) as u8;
-            leds[i as usize] = [b_val, g_val, r_val, 0];
+            leds[i as usize] = Color::rgb(r_val, g_val, b_val);
         }
         true
     }
@@ -81,7 +126,20 @@ impl SolidTimeVaryingRainbow {
 impl Pattern for SolidTimeVaryingRainbow {
     fn tick_rate(&self) -> u128 { self.tick_rate }
     fn tick_cycle(&self) -> Option<u128> { self.tick_cycle }
-    fn tick(&mut self, tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn params(&self) -> Value {
+        json!(RainbowArgs { tick_rate: self.tick_rate, saturation: self.saturation, lightness: self.lightness, brightness: self.brightness })
+    }
+    fn set_params(&mut self, params: Value) -> Result<(), String> {
+        let args: RainbowArgs = parse_args(params)?;
+        self.tick_rate = args.tick_rate;
+        self.saturation = args.saturation;
+        self.lightness = args.lightness;
+        self.brightness = args.brightness;
+        self.color.s = args.saturation;
+        self.color.l = args.lightness;
+        Ok(())
+    }
+    fn tick(&mut self, tick: u128, leds: &mut Vec<Color>) -> bool {
         if !self.logged {
             self.logged = true;
         }
@@ -91,7 +149,7 @@ impl Pattern for SolidTimeVaryingRainbow {
         let g_val = (rgb.1 as f64 * self.brightness) as u8;
         let b_val = (rgb.2 as f64 * self.brightness) as u8;
         for i in 0..leds.len() {
-            leds[i as usize] = [b_val, g_val, r_val, 0];
+            leds[i as usize] = Color::rgb(r_val, g_val, b_val);
         }
         true
     }
@@ -100,13 +158,13 @@ impl Pattern for SolidTimeVaryingRainbow {
 pub struct GradientPattern {
     pub tick_rate: u128,
     pub tick_cycle: Option<u128>,
-    pub start_color: [u32; 4],
-    pub end_color: [u32; 4],
+    pub start_color: Color,
+    pub end_color: Color,
     rerender: bool
 }
 
 impl GradientPattern {
-    pub fn new(start_color: [u32; 4], end_color: [u32; 4]) -> GradientPattern {
+    pub fn new(start_color: Color, end_color: Color) -> GradientPattern {
         GradientPattern {
             tick_rate: 60,
             tick_cycle: None,
@@ -117,15 +175,48 @@ impl GradientPattern {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GradientArgs { pub start_color: Color, pub end_color: Color }
+
+impl Default for GradientArgs {
+    fn default() -> GradientArgs {
+        GradientArgs { start_color: Color::rgb(100, 100, 100), end_color: Color::BLACK }
+    }
+}
+
+impl PatternArgs for GradientArgs {
+    fn describe() -> Vec<ParamSchema> {
+        vec![
+            ParamSchema::new("start_color", ParamType::Color, "Colour at the start of the strip"),
+            ParamSchema::new("end_color", ParamType::Color, "Colour at the end of the strip"),
+        ]
+    }
+}
+
 impl Pattern for GradientPattern {
     fn tick_rate(&self) -> u128 { self.tick_rate }
     fn tick_cycle(&self) -> Option<u128> { self.tick_cycle }
-    fn tick(&mut self, _tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn params(&self) -> Value {
+        json!(GradientArgs { start_color: self.start_color, end_color: self.end_color })
+    }
+    fn set_params(&mut self, params: Value) -> Result<(), String> {
+        let args: GradientArgs = parse_args(params)?;
+        self.start_color = args.start_color;
+        self.end_color = args.end_color;
+        self.rerender = false;
+        Ok(())
+    }
+    fn tick(&mut self, _tick: u128, leds: &mut Vec<Color>) -> bool {
         if !self.rerender {
+            let start = self.start_color.channels();
+            let end = self.end_color.channels();
             for i in 0..leds.len() {
-                for j in 0..3 {
-                    leds[i as usize][j] = ((self.start_color[j] * (leds.len()-i) as u32 + self.end_color[j] * i as u32) / leds.len() as u32) as u8
+                let mut channels = [0; 4];
+                for j in 0..4 {
+                    channels[j] = ((start[j] as u32 * (leds.len()-i) as u32 + end[j] as u32 * i as u32) / leds.len() as u32) as u8
                 }
+                leds[i] = Color::from_channels(channels);
             }
             return true
         }
@@ -136,12 +227,12 @@ impl Pattern for GradientPattern {
 pub struct SolidPattern {
     pub tick_rate: u128,
     pub tick_cycle: Option<u128>,
-    pub color: [u8; 4],
+    pub color: Color,
     rerender: bool
 }
 
 impl SolidPattern {
-    pub fn new(color: [u8; 4]) -> SolidPattern {
+    pub fn new(color: Color) -> SolidPattern {
         SolidPattern {
             tick_rate: 60, 
             color,
@@ -150,16 +241,40 @@ impl SolidPattern {
         }
     }
 
-    pub fn set_color(&mut self, color: [u8; 4]) {
+    pub fn set_color(&mut self, color: Color) {
         self.color = color;
         self.rerender = false;
     }
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SolidArgs { pub color: Color }
+
+impl Default for SolidArgs {
+    fn default() -> SolidArgs {
+        SolidArgs { color: Color::rgb(100, 100, 100) }
+    }
+}
+
+impl PatternArgs for SolidArgs {
+    fn describe() -> Vec<ParamSchema> {
+        vec![ParamSchema::new("color", ParamType::Color, "Colour of every LED")]
+    }
+}
+
 impl Pattern for SolidPattern {
     fn tick_rate(&self) -> u128 { self.tick_rate }
     fn tick_cycle(&self) -> Option<u128> { self.tick_cycle }
-    fn tick(&mut self, _tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn params(&self) -> Value {
+        json!(SolidArgs { color: self.color })
+    }
+    fn set_params(&mut self, params: Value) -> Result<(), String> {
+        let args: SolidArgs = parse_args(params)?;
+        self.set_color(args.color);
+        Ok(())
+    }
+    fn tick(&mut self, _tick: u128, leds: &mut Vec<Color>) -> bool {
         if !self.rerender {
             for i in 0..leds.len() {
                 leds[i as usize] = self.color;
@@ -173,30 +288,78 @@ impl Pattern for SolidPattern {
 pub struct FadePattern {
     pub tick_rate: u128,
     pub tick_cycle: Option<u128>,
-    color: [u8; 4],
+    color: Color,
     lights: Vec<(usize, f32)>,
     num_dots: usize,
+    seed: Option<u64>,
     rand: StdRng
 }
 
 #[allow(dead_code)]
 impl FadePattern {
-    pub fn new(tick_rate: u128, color: [u8; 4], num_dots: usize) -> FadePattern {
+    pub fn new(tick_rate: u128, color: Color, num_dots: usize) -> FadePattern {
         FadePattern {
             tick_rate,
             tick_cycle: None,
             color,
             num_dots,
+            seed: None,
             rand: StdRng::from_entropy(),
             lights: Vec::new()
         }
     }
+
+    // Places the dots the same way every time the pattern is built with this seed
+    pub fn seed(mut self, seed: u64) -> FadePattern {
+        self.seed = Some(seed);
+        self.rand = StdRng::seed_from_u64(seed);
+        self
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FadeArgs { pub tick_rate: u128, pub color: Color, pub num_dots: usize, pub seed: Option<u64> }
+
+impl Default for FadeArgs {
+    fn default() -> FadeArgs {
+        FadeArgs { tick_rate: 30, color: Color::rgb(100, 100, 100), num_dots: 40, seed: None }
+    }
+}
+
+impl PatternArgs for FadeArgs {
+    fn describe() -> Vec<ParamSchema> {
+        vec![
+            tick_rate_schema("How fast the dots fade, a twentieth of the way per tick"),
+            ParamSchema::new("color", ParamType::Color, "Colour of a dot when it lights up"),
+            ParamSchema::new("num_dots", ParamType::Integer, "Most dots lit at once").range(0.0, 10000.0).units("leds"),
+            ParamSchema::new("seed", ParamType::Integer, "Repeats the same dots every time, random when unset"),
+        ]
+    }
 }
 
 impl Pattern for FadePattern {
     fn tick_rate(&self) -> u128 { self.tick_rate }
     fn tick_cycle(&self) -> Option<u128> { self.tick_cycle }
-    fn tick(&mut self, _tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn params(&self) -> Value {
+        json!(FadeArgs { tick_rate: self.tick_rate, color: self.color, num_dots: self.num_dots, seed: self.seed })
+    }
+    fn set_params(&mut self, params: Value) -> Result<(), String> {
+        // Dots that are already lit carry on fading in the new colour
+        let args: FadeArgs = parse_args(params)?;
+        self.tick_rate = args.tick_rate;
+        self.color = args.color;
+        self.num_dots = args.num_dots;
+        if args.seed != self.seed {
+            self.seed = args.seed;
+            self.rand = match args.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+        }
+        Ok(())
+    }
+    fn tick(&mut self, _tick: u128, leds: &mut Vec<Color>) -> bool {
         if self.lights.len() < self.num_dots {
             let pos: usize = self.rand.gen_range(0, leds.len());
             let initial_brightness: f32 = self.rand.gen_range(0.1, 1.0);
@@ -206,12 +369,9 @@ impl Pattern for FadePattern {
         for (pos, brightness) in self.lights.iter_mut() {
             *brightness -= 0.05;
             if *brightness > 0.0 {
-                let b_val: u8 = (self.color[0] as f32 * *brightness) as u8;
-                let g_val: u8 = (self.color[1] as f32 * *brightness) as u8;
-                let r_val: u8 = (self.color[2] as f32 * *brightness) as u8;
-                leds[*pos] = [b_val, g_val, r_val, 0];
+                leds[*pos] = self.color.scale(*brightness as f64);
             } else {
-                leds[*pos] = [0, 0, 0, 0];
+                leds[*pos] = Color::BLACK;
             }
         }
 
@@ -225,13 +385,13 @@ pub struct FadingCrawl {
     tick_rate: u128,
     tick_cycle: Option<u128>,
     tail_len: u128,
-    color: [u8; 4],
+    color: Color,
     start_pos: u128
 }
 
 #[allow(dead_code)]
 impl FadingCrawl {
-    pub fn new(tick_rate: u128, tail_len: u128, color: [u8; 4], start_pos: u128) -> FadingCrawl {
+    pub fn new(tick_rate: u128, tail_len: u128, color: Color, start_pos: u128) -> FadingCrawl {
         FadingCrawl {
             tick_rate,
             tick_cycle: None,
@@ -242,22 +402,56 @@ impl FadingCrawl {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FadeCrawlArgs { pub tick_rate: u128, pub tail_len: u128, pub color: Color, pub start_pos: u128 }
+
+impl Default for FadeCrawlArgs {
+    fn default() -> FadeCrawlArgs {
+        FadeCrawlArgs { tick_rate: 40, tail_len: 40, color: Color::rgb(100, 100, 100), start_pos: 0 }
+    }
+}
+
+impl PatternArgs for FadeCrawlArgs {
+    fn describe() -> Vec<ParamSchema> {
+        vec![
+            tick_rate_schema("How fast the head moves, one LED per tick"),
+            // The tail is faded by dividing by the position in it, which has to fit in a u8
+            ParamSchema::new("tail_len", ParamType::Integer, "Length of the fading tail").range(1.0, 255.0).units("leds"),
+            ParamSchema::new("color", ParamType::Color, "Colour of the head"),
+            ParamSchema::new("start_pos", ParamType::Integer, "Where the head starts").range(0.0, 100000.0).units("leds"),
+        ]
+    }
+}
+
 impl Pattern for FadingCrawl {
     fn tick_rate(&self) -> u128 { self.tick_rate }
     fn tick_cycle(&self) -> Option<u128> { self.tick_cycle }
-    fn tick(&mut self, tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn params(&self) -> Value {
+        json!(FadeCrawlArgs { tick_rate: self.tick_rate, tail_len: self.tail_len, color: self.color, start_pos: self.start_pos })
+    }
+    fn set_params(&mut self, params: Value) -> Result<(), String> {
+        let args: FadeCrawlArgs = parse_args(params)?;
+        self.tick_rate = args.tick_rate;
+        self.tail_len = args.tail_len;
+        self.color = args.color;
+        self.start_pos = args.start_pos;
+        Ok(())
+    }
+    fn tick(&mut self, tick: u128, leds: &mut Vec<Color>) -> bool {
         let calc_tick = tick + self.tail_len + self.start_pos;
 
         for i in 0..self.tail_len {
             let led = (calc_tick - i) % leds.len() as u128;
-            let b_brightness = self.color[0] / (i + 1) as u8;
-            let g_brightness = self.color[1] / (i + 1) as u8;
-            let r_brightness = self.color[2] / (i + 1) as u8;
+            let r_brightness = self.color.r / (i + 1) as u8;
+            let g_brightness = self.color.g / (i + 1) as u8;
+            let b_brightness = self.color.b / (i + 1) as u8;
+            let w_brightness = self.color.w / (i + 1) as u8;
             
-            leds[led as usize] = [b_brightness, g_brightness, r_brightness, 0];
+            leds[led as usize] = Color::rgbw(r_brightness, g_brightness, b_brightness, w_brightness);
         }
         let led = (calc_tick - self.tail_len) % leds.len() as u128;
-        leds[led as usize] = [0, 0, 0, 0];
+        leds[led as usize] = Color::BLACK;
 
         true
     }
@@ -266,17 +460,138 @@ impl Pattern for FadingCrawl {
 pub trait Pattern: Send {
     fn tick_rate(&self) -> u128;
     fn tick_cycle(&self) -> Option<u128>;
-    fn start_tick(&mut self, raw_tick: u128, leds: &mut Vec<[u8; 4]>) -> bool {
+    fn start_tick(&mut self, raw_tick: u128, leds: &mut Vec<Color>) -> bool {
         match self.tick_cycle() {
             Some(cycle) => self.tick(raw_tick % cycle, leds),
             None => self.tick(raw_tick, leds)
         }
     }
-    fn tick(&mut self, tick: u128, leds: &mut Vec<[u8; 4]>) -> bool;  // Returns whether to re-render this one
+    fn tick(&mut self, tick: u128, leds: &mut Vec<Color>) -> bool;  // Returns whether to re-render this one
     fn elapsed_to_raw_tick(&self, elapsed: u128) -> u128 {
         // Gets the current tick based on the time elapsed in milliseconds
         (self.tick_rate() * elapsed) / 1000
     }
+    // Current parameters, in the same shape as the args the pattern is built from
+    fn params(&self) -> Value {
+        json!({})
+    }
+    // Replaces every parameter while the animation carries on from where it is
+    fn set_params(&mut self, _params: Value) -> Result<(), String> {
+        Err("pattern has no parameters".to_string())
+    }
+}
+
+// Parameters a pattern is built from. Fields left out take their default, fields the
+// pattern doesn't have are rejected, and numbers are checked against the ranges in the schema.
+pub trait PatternArgs: DeserializeOwned + Serialize + Default {
+    fn describe() -> Vec<ParamSchema>;
+
+    fn check(&self) -> Result<(), String> {
+        let values = json!(self);
+        for param in Self::describe() {
+            let value = values.get(param.name).and_then(Value::as_f64);
+            if let (Some(value), Some(min), Some(max)) = (value, param.min, param.max) {
+                check_range(param.name, value, min, max)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The schema with the defaults filled in
+    fn schema() -> Vec<ParamSchema> {
+        let defaults = json!(Self::default());
+        Self::describe().into_iter()
+            .map(|mut param| {
+                param.default = defaults.get(param.name).cloned().unwrap_or(Value::Null);
+                param
+            })
+            .collect()
+    }
+}
+
+pub fn parse_args<A: PatternArgs>(params: Value) -> Result<A, String> {
+    let args: A = serde_json::from_value(params).map_err(|err| err.to_string())?;
+    args.check()?;
+    Ok(args)
+}
+
+// Overwrites the fields of `current` that also appear in `changes`, descending into
+// nested objects so a partial object only replaces the keys it names. A key `current`
+// doesn't have is an error rather than being dropped, so a typo can't look like it worked.
+// `path` is where `current` sits in the whole value, for naming keys in errors.
+pub(crate) fn merge(current: &mut Value, changes: &Value, path: &str) -> Result<(), String> {
+    let changes = match changes.as_object() {
+        Some(changes) => changes,
+        None if path.is_empty() => return Err(format!("expected an object, got {}", changes)),
+        None => return Err(format!("expected an object for {:?}, got {}", path, changes)),
+    };
+    let current = match current.as_object_mut() {
+        Some(current) => current,
+        None => return Err(format!("{:?} isn't an object", path)),
+    };
+    for (key, value) in changes {
+        let key_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        match current.get_mut(key) {
+            Some(existing) if existing.is_object() => merge(existing, value, &key_path)?,
+            Some(existing) => *existing = value.clone(),
+            None => return Err(format!("unknown key {:?}", key_path)),
+        }
+    }
+    Ok(())
+}
+
+// Describes one parameter of a pattern so a UI can build a control for it
+#[derive(Serialize, Clone, Debug)]
+pub struct ParamSchema {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: ParamType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub units: Option<&'static str>,
+    pub default: Value,
+    pub description: &'static str,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    Integer,
+    Number,
+    // Anything Color deserializes from, given as a hex string
+    Color,
+}
+
+impl ParamSchema {
+    pub fn new(name: &'static str, kind: ParamType, description: &'static str) -> ParamSchema {
+        ParamSchema { name, kind, min: None, max: None, units: None, default: Value::Null, description }
+    }
+
+    pub fn range(mut self, min: f64, max: f64) -> ParamSchema {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+
+    pub fn units(mut self, units: &'static str) -> ParamSchema {
+        self.units = Some(units);
+        self
+    }
+}
+
+fn tick_rate_schema(description: &'static str) -> ParamSchema {
+    ParamSchema::new("tick_rate", ParamType::Integer, description).range(1.0, 1000.0).units("ticks/s")
+}
+
+pub(crate) fn check_range(field: &str, value: f64, min: f64, max: f64) -> Result<(), String> {
+    if value >= min && value <= max {
+        Ok(())
+    } else {
+        Err(format!("{} must be between {} and {}, got {}", field, min, max, value))
+    }
 }
 
 pub struct Runner {
@@ -294,11 +609,15 @@ impl Runner {
         thread::spawn(move || {
             // let mut lock_count = 0;
             let mut fail_count = 0;
-            let manager = threaded_manager.lock().unwrap();
+            let manager = threaded_manager.lock().unwrap_or_else(PoisonError::into_inner);
             let mut sleep_time = manager.sleep_time;
             drop(manager);
             loop {
-                let mut lock = threaded_manager.try_lock();
+                // Keep ticking even if a command handler panicked while holding the lock
+                let mut lock = match threaded_manager.try_lock() {
+                    Err(TryLockError::Poisoned(poisoned)) => Ok(poisoned.into_inner()),
+                    lock => lock,
+                };
                 if let Ok(ref mut mutex) = lock {
                     // println!("Got manager: {}", lock_count);
                     // lock_count += 1;
@@ -317,151 +636,1033 @@ impl Runner {
     }
 }
 
+// How often frames are re-sent while dithering a frame that isn't changing otherwise
+const DITHER_INTERVAL: time::Duration = time::Duration::from_millis(4);
+// Values closer than this to a whole step aren't worth dithering
+const DITHER_THRESHOLD: f32 = 0.01;
+// How often frames are rendered while a transition is running
+const TRANSITION_INTERVAL: time::Duration = time::Duration::from_millis(16);
+
+// Snapshot of what a manager is showing, see PatternManager::get_state
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManagerState {
+    pub brightness: f64,
+    // Bottom of the stack first
+    pub layers: Vec<LayerState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LayerState {
+    pub name: String,
+    // Registered pattern type, unset for layers added directly rather than by type
+    pub pattern: Option<String>,
+    pub params: Value,
+    #[serde(default)]
+    pub tick: u128,
+    #[serde(default)]
+    pub z_index: i32,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default = "full_opacity")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub region: Region,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub solo: bool,
+}
+
+fn full_opacity() -> f32 {
+    1.0
+}
+
 struct PatternStore {
+    name: String,
+    // Registry name of the pattern, when it was built from one
+    pattern_type: Option<String>,
+    z_index: i32,
     pattern: Box<dyn Pattern>,
-    leds: Vec<[u8; 4]>,
+    leds: Vec<Color>,
     curr_tick: u128,
-    start_time: time::Instant
+    start_time: time::Instant,
+    blend: BlendMode,
+    opacity: f32,
+    region: Region,
+    // Strip pixels for each pixel of `leds`
+    targets: Vec<Vec<usize>>,
+    // When the layer was paused, it keeps its last frame until resumed
+    paused_at: Option<time::Instant>,
+    // Left out of the frame
+    muted: bool,
+    // While any layer is soloed only soloed layers are shown
+    solo: bool,
 }
 
 pub struct PatternManager {
     creation_time: time::Instant,
     sleep_time: time::Duration,
-    patterns: HashMap<String, PatternStore>,
-    controller: Controller,
-    num_leds: i32,
+    // Layers from the bottom of the stack to the top, kept sorted by z_index
+    patterns: Vec<PatternStore>,
+    // Layers being faded out by the running transition
+    outgoing: Vec<PatternStore>,
+    // The mixed frame at the moment a transition was started over a running one, shown in
+    // place of the outgoing layers so the new fade picks up where the old one was
+    frozen: Option<Vec<[f32; 4]>>,
+    transition: Option<Transition>,
+    output: Box<dyn Output>,
+    num_leds: usize,
+    // Scale applied to the whole frame, from 0 to 1. Kept as it was set so snapshots report
+    // the same value back.
+    brightness: f64,
+    white: WhiteMode,
+    correction: Correction,
+    correction_curve: CorrectionCurve,
+    power: PowerModel,
+    // Set when the limit is shared with other zones
+    power_share: Option<PowerShare>,
+    power_report: PowerReport,
+    precise: Vec<[f32; 4]>,
+    dither_error: Vec<[f32; 4]>,
+    dithering: bool,
+    dither_pending: bool,
+    last_render: time::Instant,
+    // Frames from realtime inputs in the order their sources started. The newest one is shown
+    // in place of the patterns.
+    externals: Vec<External>,
 }
 
-impl PatternManager {
-    pub fn new(pin: i32, led_count: i32) -> PatternManager {
-        let channel = ChannelBuilder::new()
-            .pin(pin)
-            .count(led_count)
-            .strip_type(StripType::Ws2811Grb)
-            .brightness(255)
-            .build();
-
-        let controller = ControllerBuilder::new()
-            .freq(800_000)
-            .dma(10)
-            .channel(0, channel)
-            .build()
-            .expect("Failed to create controllerBuilder");
+// The latest frame from one realtime input, with channels from 0 to 1
+struct External {
+    source: String,
+    leds: Vec<[f32; 4]>,
+    expires: Option<time::Instant>,
+}
 
+impl PatternManager {
+    pub fn new(output: Box<dyn Output>) -> PatternManager {
+        let num_leds = output.num_leds();
         let pattern_manager = PatternManager {
             creation_time: time::Instant::now(),
             sleep_time: time::Duration::from_micros(1_000),
-            patterns: HashMap::new(),
-            controller: controller,
-            num_leds: led_count,
+            patterns: Vec::new(),
+            outgoing: Vec::new(),
+            frozen: None,
+            transition: None,
+            num_leds,
+            output,
+            brightness: 1.0,
+            white: WhiteMode::default(),
+            correction: Correction::default(),
+            correction_curve: Correction::default().curve(),
+            power: PowerModel::default(),
+            power_share: None,
+            power_report: PowerReport::default(),
+            precise: vec![[0.0; 4]; num_leds],
+            dither_error: vec![[0.0; 4]; num_leds],
+            dithering: false,
+            dither_pending: false,
+            last_render: time::Instant::now(),
+            externals: Vec::new(),
         };
         pattern_manager
     }
 
-    pub fn set_brightness(&mut self, brightness: u8) {
-        self.controller.set_brightness(0, brightness);
+    pub fn set_brightness(&mut self, brightness: f64) {
+        self.brightness = brightness.clamp(0.0, 1.0);
+        self.tick();
+    }
+
+    pub fn set_white_mode(&mut self, white: WhiteMode) {
+        self.white = white;
+        self.tick();
     }
 
+    pub fn set_correction(&mut self, correction: Correction) {
+        self.correction = correction;
+        self.correction_curve = correction.curve();
+        self.tick();
+    }
+
+    pub fn get_correction(&self) -> Correction {
+        self.correction
+    }
+
+    pub fn set_power_model(&mut self, power: PowerModel) {
+        self.power = power;
+        self.tick();
+    }
+
+    // Makes the power limit cover this manager together with every other one in the budget
+    pub fn set_power_budget(&mut self, budget: &PowerBudget) {
+        self.power_share = Some(budget.join());
+        self.tick();
+    }
+
+    // Current draw of the last frame as estimated by the power model
+    pub fn get_power(&self) -> PowerReport {
+        self.power_report
+    }
+
+    // Has no effect on outputs that don't support dithering
+    pub fn set_dithering(&mut self, dithering: bool) {
+        self.dithering = dithering && self.output.supports_dithering();
+        for error in self.dither_error.iter_mut() {
+            *error = [0.0; 4];
+        }
+        self.tick();
+    }
+
+    // New layers go on top of the stack. Adding a name that is already in use replaces that
+    // layer's pattern in place.
     pub fn add_pattern(&mut self, name: String, pattern: Box<dyn Pattern>) {
+        self.add_pattern_with_region(name, pattern, Region::default());
+    }
+
+    // Adds a layer that only covers part of the strip. Pixels outside the region show the
+    // layers underneath.
+    pub fn add_pattern_with_region(&mut self, name: String, pattern: Box<dyn Pattern>, region: Region) {
+        let targets = region.map(self.num_leds);
+        let z_index = match self.find(&name) {
+            Some(i) => self.patterns.remove(i).z_index,
+            None => self.patterns.last().map_or(0, |top| top.z_index + 1),
+        };
         let store = PatternStore {
+            name,
+            pattern_type: None,
+            z_index,
             pattern: pattern,
-            leds: vec![[0, 0, 0, 0]; self.num_leds as usize],
+            leds: vec![Color::BLACK; targets.len()],
             curr_tick: 0,
             start_time: time::Instant::now(),
+            blend: BlendMode::default(),
+            opacity: 1.0,
+            region,
+            targets,
+            paused_at: None,
+            muted: false,
+            solo: false,
         };
-        self.patterns.insert(name, store);
+        let position = self.patterns.iter().position(|layer| layer.z_index > z_index).unwrap_or(self.patterns.len());
+        self.patterns.insert(position, store);
     }
 
     pub fn remove_pattern(&mut self, name: String) -> bool {
-        if self.patterns.contains_key(&name) {
-            self.patterns.remove(&name);
+        if let Some(i) = self.find(&name) {
+            self.patterns.remove(i);
+            self.tick();
             return true;
         } else {
             return false;
         }
     }
 
+    // Changes how a layer is composited, returning false when there is no such layer
+    pub fn set_blend(&mut self, name: &str, blend: BlendMode, opacity: f32) -> bool {
+        match self.find(name) {
+            Some(i) => {
+                let store = &mut self.patterns[i];
+                store.blend = blend;
+                store.opacity = opacity.clamp(0.0, 1.0);
+                self.tick();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Moves the current layers out to be replaced by the ones added afterwards. Until the
+    // transition is done both sets keep running and their frames are mixed together.
+    // Starting one while another is still running fades out from the frame as it was mixed.
+    pub fn start_transition(&mut self, style: TransitionStyle, duration: time::Duration) {
+        if self.transition.as_ref().is_some_and(|transition| !transition.is_done()) {
+            self.frozen = Some(self.compose());
+            self.outgoing.clear();
+            self.patterns.clear();
+        } else {
+            self.frozen = None;
+            self.outgoing = std::mem::take(&mut self.patterns);
+        }
+        self.transition = Some(Transition::new(style, duration, self.num_leds));
+    }
+
+    fn end_transition(&mut self) {
+        self.transition = None;
+        self.outgoing.clear();
+        self.frozen = None;
+    }
+
+    pub fn get_pattern_params(&self, name: &str) -> Option<Value> {
+        self.find(name).map(|i| self.patterns[i].pattern.params())
+    }
+
+    // Updates a running pattern without restarting it. Params left out of `patch` keep their
+    // current value. Fails when there is no such layer or the pattern rejects the params.
+    pub fn set_pattern_params(&mut self, name: &str, patch: Value) -> Result<(), String> {
+        let i = self.find(name).ok_or_else(|| format!("no pattern named {:?}", name))?;
+        let store = &mut self.patterns[i];
+        let mut params = store.pattern.params();
+        merge(&mut params, &patch, "")?;
+        let old_rate = store.pattern.tick_rate();
+        store.pattern.set_params(params)?;
+
+        if store.pattern.tick_rate() != old_rate {
+            // Otherwise the animation would jump ahead or stall until the clock catches up
+            align_start_time(store);
+        }
+        self.tick();
+        Ok(())
+    }
+
+    // Records which registered pattern type a layer was built from, so it can be rebuilt
+    // from a snapshot
+    pub fn set_pattern_type(&mut self, name: &str, pattern_type: &str) -> bool {
+        match self.find(name) {
+            Some(i) => {
+                self.patterns[i].pattern_type = Some(pattern_type.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_state(&self) -> ManagerState {
+        let layers = self.patterns.iter()
+            .map(|layer| LayerState {
+                name: layer.name.clone(),
+                pattern: layer.pattern_type.clone(),
+                params: layer.pattern.params(),
+                tick: layer.curr_tick,
+                z_index: layer.z_index,
+                blend: layer.blend,
+                opacity: layer.opacity,
+                region: layer.region.clone(),
+                paused: layer.paused_at.is_some(),
+                muted: layer.muted,
+                solo: layer.solo,
+            })
+            .collect();
+        ManagerState { brightness: self.brightness, layers }
+    }
+
+    // Replaces every layer and the brightness at once. `patterns` holds a pattern built from
+    // each layer's type and params, in the same order as the layers.
+    pub fn set_state(&mut self, state: &ManagerState, patterns: Vec<Box<dyn Pattern>>) {
+        self.brightness = state.brightness.clamp(0.0, 1.0);
+        self.end_transition();
+
+        let now = time::Instant::now();
+        let num_leds = self.num_leds;
+        self.patterns = state.layers.iter().zip(patterns)
+            .map(|(layer, pattern)| {
+                let targets = layer.region.map(num_leds);
+                let mut store = PatternStore {
+                    name: layer.name.clone(),
+                    pattern_type: layer.pattern.clone(),
+                    z_index: layer.z_index,
+                    pattern,
+                    leds: vec![Color::BLACK; targets.len()],
+                    curr_tick: layer.tick,
+                    start_time: now,
+                    blend: layer.blend,
+                    opacity: layer.opacity.clamp(0.0, 1.0),
+                    region: layer.region.clone(),
+                    targets,
+                    paused_at: if layer.paused { Some(now) } else { None },
+                    muted: layer.muted,
+                    solo: layer.solo,
+                };
+                if !store.leds.is_empty() {
+                    // Draw the frame of the tick it was at instead of waiting for the next one
+                    store.pattern.start_tick(layer.tick, &mut store.leds);
+                }
+                align_start_time(&mut store);
+                store
+            })
+            .collect();
+        self.patterns.sort_by_key(|layer| layer.z_index);
+        self.tick();
+    }
+
+    pub fn set_paused(&mut self, name: &str, paused: bool) -> bool {
+        match self.find(name) {
+            Some(i) => {
+                let store = &mut self.patterns[i];
+                match (paused, store.paused_at) {
+                    (true, None) => store.paused_at = Some(time::Instant::now()),
+                    (false, Some(paused_at)) => {
+                        // Pick up from the tick it was paused on instead of jumping ahead
+                        store.start_time += paused_at.elapsed();
+                        store.paused_at = None;
+                    }
+                    _ => {}
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_muted(&mut self, name: &str, muted: bool) -> bool {
+        match self.find(name) {
+            Some(i) => {
+                self.patterns[i].muted = muted;
+                self.tick();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_solo(&mut self, name: &str, solo: bool) -> bool {
+        match self.find(name) {
+            Some(i) => {
+                self.patterns[i].solo = solo;
+                self.tick();
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn clear(&mut self) {
         self.patterns.clear();
+        self.end_transition();
         self.tick();
     }
 
+    // Layer names from the bottom of the stack to the top
     pub fn get_patterns(&mut self) -> Vec<String> {
-        self.patterns.keys().map(|key| key.clone()).collect()
+        self.patterns.iter().map(|layer| layer.name.clone()).collect()
+    }
+
+    // Moves a layer past the one above it, trading z-indexes with it
+    pub fn raise_pattern(&mut self, name: &str) -> bool {
+        match self.find(name) {
+            Some(i) if i + 1 < self.patterns.len() => {
+                self.swap_layers(i, i + 1);
+                true
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    // Moves a layer below the one beneath it, trading z-indexes with it
+    pub fn lower_pattern(&mut self, name: &str) -> bool {
+        match self.find(name) {
+            Some(i) if i > 0 => {
+                self.swap_layers(i - 1, i);
+                true
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    // Layers with the same z-index keep the order they were in
+    pub fn set_z_index(&mut self, name: &str, z_index: i32) -> bool {
+        match self.find(name) {
+            Some(i) => {
+                self.patterns[i].z_index = z_index;
+                self.patterns.sort_by_key(|layer| layer.z_index);
+                self.tick();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn swap_layers(&mut self, lower: usize, upper: usize) {
+        let z_index = self.patterns[lower].z_index;
+        self.patterns[lower].z_index = self.patterns[upper].z_index;
+        self.patterns[upper].z_index = z_index;
+        self.patterns.swap(lower, upper);
+        self.tick();
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.patterns.iter().position(|layer| layer.name == name)
     }
 
     pub fn increment_ticks(&mut self) {
         let mut got_update = false;
-        
-        for (_name, pattern_holder) in self.patterns.iter_mut(){
-            let elapsed = pattern_holder.start_time.elapsed().as_millis();
-            let old_tick = pattern_holder.curr_tick;
-            let curr_tick = pattern_holder.pattern.elapsed_to_raw_tick(elapsed);
-
-            if curr_tick > old_tick {
-                let leds = &mut pattern_holder.leds;
-                // Only run if there is going to be an update
-                if curr_tick - old_tick > 1 {
-                    println!("Catching up on {} by {} ticks", _name, curr_tick-old_tick);
-                }
-                for j in old_tick..curr_tick {
-                    // This runs the number of times that the pattern should tick
-                    if pattern_holder.pattern.start_tick(j + 1, leds) {
-                        got_update = true;
-                    }
-                }
-                pattern_holder.curr_tick = curr_tick;
+
+        let now = time::Instant::now();
+        let sources = self.externals.len();
+        // A stream that stopped sending is dropped so what is beneath shows again
+        self.externals.retain(|external| external.expires.is_none_or(|expires| now < expires));
+        if self.externals.len() != sources {
+            got_update = true;
+        }
+
+        if self.power_share.as_ref().is_some_and(PowerShare::is_stale) {
+            // Another zone's draw changed, so this frame's share of the budget did too
+            got_update = true;
+        }
+
+        if tick_layers(&mut self.patterns) | tick_layers(&mut self.outgoing) {
+            got_update = true;
+        }
+
+        match self.transition.as_ref().map(Transition::is_done) {
+            Some(true) => {
+                self.end_transition();
+                got_update = true;
             }
+            Some(false) if self.last_render.elapsed() >= TRANSITION_INTERVAL => got_update = true,
+            _ => {}
         }
 
         if got_update {
-            // Then we need to render the controller
+            // Then we need to render the output
             self.tick();
+        } else if self.dither_pending && self.last_render.elapsed() >= DITHER_INTERVAL {
+            // Nothing changed but the dithered values need fresh frames to average out
+            self.render();
+        }
+
+        if let Err(err) = self.output.flush() {
+            eprintln!("Failed to render leds: {}", err);
+        }
+    }
+
+    // Steps every pattern forward by a fixed number of ticks without looking at the clock and
+    // renders the result. Lets a manager that has no Runner be driven deterministically.
+    pub fn advance(&mut self, ticks: u128) {
+        advance_layers(&mut self.patterns, ticks);
+        advance_layers(&mut self.outgoing, ticks);
+        self.tick();
+    }
+
+    // Shows a frame from an outside source instead of the patterns, which keep running
+    // underneath. Each source has its own frame, which stays up until the source is released
+    // or, when a timeout is given, until no new frame arrives from it in time. While several
+    // sources are live the one that started last is shown.
+    pub fn show_external(&mut self, source: &str, leds: &[Color], timeout: Option<time::Duration>) {
+        let i = match self.externals.iter().position(|external| external.source == source) {
+            Some(i) => i,
+            None => {
+                self.externals.push(External {
+                    source: source.to_string(),
+                    leds: vec![[0.0; 4]; self.num_leds],
+                    expires: None,
+                });
+                self.externals.len() - 1
+            }
         };
+        let external = &mut self.externals[i];
+        for (slot, led) in external.leds.iter_mut().zip(leds) {
+            *slot = led.channels().map(|channel| channel as f32 / 255.0);
+        }
+        external.expires = timeout.map(|timeout| time::Instant::now() + timeout);
+        self.tick();
+    }
+
+    pub fn release_external(&mut self, source: &str) {
+        let sources = self.externals.len();
+        self.externals.retain(|external| external.source != source);
+        if self.externals.len() != sources {
+            self.tick();
+        }
+    }
+
+    pub fn num_leds(&self) -> usize {
+        self.num_leds
     }
 
     pub fn tick(&mut self) {
-        let leds = self.controller.leds_mut(0);
+        let frame = match self.externals.last() {
+            Some(external) => external.leds.clone(),
+            None => self.compose(),
+        };
+        let has_white = self.output.has_white();
+        let brightness = self.brightness as f32;
+        for (led, precise) in frame.iter().zip(self.precise.iter_mut()) {
+            let mut channels = led.map(|channel| channel * 255.0 * brightness);
+            if has_white {
+                channels = self.white.extract_channels(channels);
+            }
+            *precise = self.correction_curve.apply(channels);
+        }
+        self.power_report = match self.power_share {
+            Some(ref mut share) => self.power.limit_shared(&mut self.precise, share),
+            None => self.power.limit(&mut self.precise),
+        };
 
-        for i in 0..self.num_leds {
-            let mut led: [u32; 4] = [0, 0, 0, 0];
-            for (_name, pattern_manager) in self.patterns.iter() {
-                for l in 0..4 {
-                    led[l as usize] += pattern_manager.leds[i as usize][l as usize] as u32; // / len;
+        self.render();
+    }
+
+    // Quantises the precise frame to 8 bits and sends it to the output. With dithering the
+    // rounding error of every channel is carried into the next frame, so values between two
+    // steps come out right on average as long as frames keep being rendered.
+    fn render(&mut self) {
+        let mut frame = Vec::with_capacity(self.num_leds);
+        let mut fractional = false;
+        for (precise, error) in self.precise.iter().zip(self.dither_error.iter_mut()) {
+            let mut channels = [0; 4];
+            for l in 0..4 {
+                if self.dithering {
+                    let value = precise[l] + error[l];
+                    let quantised = value.round().clamp(0.0, 255.0);
+                    error[l] = value - quantised;
+                    channels[l] = quantised as u8;
+                    fractional |= precise[l].fract() > DITHER_THRESHOLD;
+                } else {
+                    channels[l] = precise[l].round() as u8;
                 }
             }
+            frame.push(Color::from_channels(channels));
+        }
+        self.dither_pending = fractional;
+        self.last_render = time::Instant::now();
 
-            if false {
-                // let max = leds[i as usize].iter().max().expect("Could not get max for brightness");
-                let mut max = 0;
-                let brightness: i32 = 255;
-                for k in 0..3 {
-                    if led[k] > max { max = led[k] };
-                }
-                if max > 0 {
-                    for k in 0..3 {
-                        let val = led[k] as u32 * brightness as u32 / max as u32;
-                        led[k] = val;
-                    }
-                }
-            } else {
-                let mut max = 0;
-                for k in 0..3 {
-                    if led[k] > max { max = led[k] };
-                }
-                if max > 255 {
-                    for k in 0..3 {
-                        let val = led[k] as u32 * 255 as u32 / max as u32;
-                        led[k] = val;
-                    }
+        if let Err(err) = self.output.render(&frame) {
+            eprintln!("Failed to render leds: {}", err);
+        }
+    }
+
+    // The layers blended into a frame with channels from 0 to 1. It stays in floats until
+    // render so the dithering has the full precision to work with.
+    fn compose(&self) -> Vec<[f32; 4]> {
+        let mut frame = compose_layers(&self.patterns, self.num_leds);
+        if let Some(ref transition) = self.transition {
+            let mut outgoing = match self.frozen {
+                Some(ref frozen) => frozen.clone(),
+                None => compose_layers(&self.outgoing, self.num_leds),
+            };
+            transition.mix(&mut outgoing, &frame);
+            frame = outgoing;
+        }
+        frame
+    }
+}
+
+// Moves the start of a layer so its clock reads the tick it is on at the current rate
+fn align_start_time(store: &mut PatternStore) {
+    let tick_rate = store.pattern.tick_rate();
+    if tick_rate == 0 {
+        return;
+    }
+    let elapsed = (store.curr_tick * 1000).div_ceil(tick_rate);
+    let now = store.paused_at.unwrap_or_else(time::Instant::now);
+    store.start_time = now - time::Duration::from_millis(elapsed as u64);
+}
+
+// Runs every layer up to the tick its clock says it should be at, returning whether any
+// of them changed
+fn tick_layers(layers: &mut [PatternStore]) -> bool {
+    let mut got_update = false;
+    for pattern_holder in layers.iter_mut() {
+        if pattern_holder.leds.is_empty() || pattern_holder.paused_at.is_some() {
+            // Region lies off the strip, and patterns can't tick an empty buffer
+            continue;
+        }
+        let elapsed = pattern_holder.start_time.elapsed().as_millis();
+        let old_tick = pattern_holder.curr_tick;
+        let curr_tick = pattern_holder.pattern.elapsed_to_raw_tick(elapsed);
+
+        if curr_tick > old_tick {
+            let leds = &mut pattern_holder.leds;
+            // Only run if there is going to be an update
+            if curr_tick - old_tick > 1 {
+                eprintln!("Catching up on {} by {} ticks", pattern_holder.name, curr_tick-old_tick);
+            }
+            for j in old_tick..curr_tick {
+                // This runs the number of times that the pattern should tick
+                if pattern_holder.pattern.start_tick(j + 1, leds) {
+                    got_update = true;
                 }
             }
-            for l in 0..3 {
-                leds[i as usize][l] = led[l] as u8;
+            pattern_holder.curr_tick = curr_tick;
+        }
+    }
+    got_update
+}
+
+fn advance_layers(layers: &mut [PatternStore], ticks: u128) {
+    for pattern_holder in layers.iter_mut() {
+        if pattern_holder.leds.is_empty() || pattern_holder.paused_at.is_some() {
+            continue;
+        }
+        let leds = &mut pattern_holder.leds;
+        for j in pattern_holder.curr_tick..pattern_holder.curr_tick + ticks {
+            pattern_holder.pattern.start_tick(j + 1, leds);
+        }
+        pattern_holder.curr_tick += ticks;
+    }
+}
+
+// Blends a stack of layers bottom to top into a frame with channels from 0 to 1
+fn compose_layers(layers: &[PatternStore], num_leds: usize) -> Vec<[f32; 4]> {
+    let mut frame = vec![[0.0; 4]; num_leds];
+    let soloing = layers.iter().any(|layer| layer.solo);
+
+    for pattern_manager in layers.iter() {
+        if pattern_manager.muted || (soloing && !pattern_manager.solo) {
+            continue;
+        }
+        for (led, targets) in pattern_manager.leds.iter().zip(pattern_manager.targets.iter()) {
+            let channels = led.channels();
+            let mut over = [0.0; 4];
+            for (value, channel) in over.iter_mut().zip(channels.iter()) {
+                *value = *channel as f32 / 255.0;
+            }
+            for &target in targets {
+                frame[target] = blend::blend_layer(pattern_manager.blend, pattern_manager.opacity, frame[target], over);
+            }
+        }
+    }
+
+    for led in frame.iter_mut() {
+        *led = blend::normalise(*led);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{CaptureOutput, FrameCapture};
+
+    fn manager(num_leds: usize) -> (PatternManager, FrameCapture) {
+        let (output, capture) = CaptureOutput::new(num_leds);
+        (PatternManager::new(Box::new(output)), capture)
+    }
+
+    // A capture that claims the features of a real strip
+    struct StripOutput {
+        capture: CaptureOutput,
+        has_white: bool,
+    }
+
+    impl Output for StripOutput {
+        fn num_leds(&self) -> usize { self.capture.num_leds() }
+        fn render(&mut self, leds: &[Color]) -> Result<(), crate::output::OutputError> { self.capture.render(leds) }
+        fn has_white(&self) -> bool { self.has_white }
+        fn supports_dithering(&self) -> bool { true }
+    }
+
+    fn strip_manager(num_leds: usize, has_white: bool) -> (PatternManager, FrameCapture) {
+        let (capture, frames) = CaptureOutput::new(num_leds);
+        (PatternManager::new(Box::new(StripOutput { capture, has_white })), frames)
+    }
+
+    #[test]
+    fn renders_layers_through_the_output() {
+        let (mut manager, capture) = manager(3);
+        manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(10, 20, 30))));
+        manager.advance(1);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(10, 20, 30); 3]);
+
+        manager.clear();
+        assert_eq!(capture.last().unwrap().leds, vec![Color::BLACK; 3]);
+    }
+
+    #[test]
+    fn set_pattern_params_only_changes_what_the_patch_names() {
+        let (mut manager, _) = manager(2);
+        manager.add_pattern("rainbow".to_string(), Box::new(MovingRainbow::new(45, 0.5, 0.25, 1.0)));
+        manager.set_pattern_params("rainbow", json!({ "brightness": 0.5 })).unwrap();
+        let params = manager.get_pattern_params("rainbow").unwrap();
+        assert_eq!(params["tick_rate"], json!(45));
+        assert_eq!(params["saturation"], json!(0.5));
+        assert_eq!(params["brightness"], json!(0.5));
+
+        assert!(manager.set_pattern_params("rainbow", json!({ "speed": 2 })).is_err());
+        assert!(manager.set_pattern_params("missing", json!({})).is_err());
+        assert_eq!(manager.get_pattern_params("rainbow").unwrap(), params);
+    }
+
+    #[test]
+    fn merge_descends_into_objects() {
+        let mut current = json!({ "a": 1, "nested": { "b": 2, "c": 3 } });
+        merge(&mut current, &json!({ "nested": { "c": 4 } }), "").unwrap();
+        assert_eq!(current, json!({ "a": 1, "nested": { "b": 2, "c": 4 } }));
+
+        assert_eq!(merge(&mut current, &json!({ "nested": { "d": 1 } }), ""), Err("unknown key \"nested.d\"".to_string()));
+        assert!(merge(&mut current, &json!({ "nested": 5 }), "").unwrap_err().contains("\"nested\""));
+        assert!(merge(&mut current, &json!(null), "").is_err());
+    }
+
+    #[test]
+    fn seeded_fade_repeats() {
+        let run = |seed| {
+            let (mut manager, capture) = manager(20);
+            manager.add_pattern("fade".to_string(), Box::new(FadePattern::new(30, Color::rgb(200, 0, 0), 5).seed(seed)));
+            for _ in 0..10 {
+                manager.advance(1);
+            }
+            capture.frames().into_iter().map(|frame| frame.leds).collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn external_frames_replace_the_patterns() {
+        let (mut manager, capture) = manager(3);
+        manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(0, 0, 100))));
+        manager.advance(1);
+
+        // Dim and black pixels are shown as sent, and pixels the frame leaves out are black
+        manager.show_external("ddp", &[Color::rgb(255, 0, 0), Color::rgb(51, 0, 0)], None);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(255, 0, 0), Color::rgb(51, 0, 0), Color::BLACK]);
+
+        manager.release_external("ddp");
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 0, 100); 3]);
+    }
+
+    #[test]
+    fn keeps_external_sources_apart() {
+        let (mut manager, capture) = manager(2);
+        manager.show_external("opc", &[Color::rgb(10, 0, 0), Color::BLACK], None);
+        manager.show_external("wled", &[Color::BLACK, Color::rgb(0, 20, 0)], None);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::BLACK, Color::rgb(0, 20, 0)]);
+
+        // A frame from an older source doesn't take over from the newest one
+        manager.show_external("opc", &[Color::rgb(30, 0, 0), Color::BLACK], None);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::BLACK, Color::rgb(0, 20, 0)]);
+
+        // Releasing the newest falls back to the one before, with its latest frame
+        manager.release_external("wled");
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(30, 0, 0), Color::BLACK]);
+        manager.release_external("wled");
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(30, 0, 0), Color::BLACK]);
+    }
+
+    #[test]
+    fn drops_external_frames_that_time_out() {
+        let (mut manager, capture) = manager(1);
+        manager.show_external("ddp", &[Color::rgb(1, 2, 3)], Some(time::Duration::from_secs(0)));
+        manager.show_external("wled", &[Color::rgb(0, 0, 9)], Some(time::Duration::from_secs(60)));
+        manager.increment_ticks();
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 0, 9)]);
+    }
+
+    #[test]
+    fn extracts_white_only_for_rgbw_outputs() {
+        for &(has_white, expected) in [(true, Color::rgbw(50, 0, 0, 50)), (false, Color::rgb(100, 50, 50))].iter() {
+            let (mut manager, capture) = strip_manager(1, has_white);
+            manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(100, 50, 50))));
+            manager.advance(1);
+            assert_eq!(capture.last().unwrap().leds, vec![expected]);
+        }
+
+        let (mut manager, capture) = strip_manager(1, true);
+        manager.set_white_mode(WhiteMode::None);
+        manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(100, 50, 50))));
+        manager.advance(1);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(100, 50, 50)]);
+    }
+
+    fn red_sum(capture: &FrameCapture) -> u32 {
+        capture.frames().iter().map(|frame| frame.leds[0].r as u32).sum()
+    }
+
+    #[test]
+    fn dithering_averages_out_fractions() {
+        let (mut manager, capture) = strip_manager(1, false);
+        manager.set_dithering(true);
+        manager.set_brightness(0.5);
+        manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(3, 0, 0))));
+        manager.advance(1);
+        capture.clear();
+
+        // 1.5 stays a fraction through brightness and correction, so it alternates 1 and 2
+        for _ in 0..20 {
+            manager.tick();
+        }
+        assert_eq!(red_sum(&capture), 30);
+        let values: Vec<u8> = capture.frames().iter().map(|frame| frame.leds[0].r).collect();
+        assert!(values.iter().all(|&value| value == 1 || value == 2));
+    }
+
+    #[test]
+    fn dithering_rerenders_while_values_are_between_steps() {
+        let (mut manager, capture) = strip_manager(1, false);
+        manager.set_dithering(true);
+        manager.set_brightness(0.5);
+        manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(3, 0, 0))));
+        manager.advance(1);
+        let rendered = capture.len();
+
+        thread::sleep(DITHER_INTERVAL);
+        manager.increment_ticks();
+        assert!(capture.len() > rendered);
+
+        // Whole values don't need it
+        manager.set_brightness(1.0);
+        manager.tick();
+        let rendered = capture.len();
+        thread::sleep(DITHER_INTERVAL);
+        manager.increment_ticks();
+        assert_eq!(capture.len(), rendered);
+    }
+
+    #[test]
+    fn dithering_is_off_unless_supported_and_enabled() {
+        let (mut strip, strip_capture) = strip_manager(1, false);
+        let (mut preview, preview_capture) = manager(1);
+        preview.set_dithering(true);
+        for (manager, capture) in [(&mut strip, &strip_capture), (&mut preview, &preview_capture)].iter_mut() {
+            manager.set_brightness(0.5);
+            manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(3, 0, 0))));
+            manager.advance(1);
+            capture.clear();
+            for _ in 0..10 {
+                manager.tick();
             }
+            // Always rounded the same way
+            assert_eq!(red_sum(capture), 20);
         }
+    }
+
+    #[test]
+    fn zones_rescale_when_another_zone_changes() {
+        let budget = PowerBudget::new();
+        let power = PowerModel { limit_milliamps: Some(200.0), ..PowerModel::default() };
+        let mut zones: Vec<_> = (0..2).map(|_| {
+            let (mut manager, capture) = manager(4);
+            manager.set_power_model(power);
+            manager.set_power_budget(&budget);
+            manager.add_pattern("solid".to_string(), Box::new(SolidPattern::new(Color::rgb(255, 255, 255))));
+            (manager, capture)
+        }).collect();
+
+        zones[0].0.advance(1);
+        assert!((zones[0].0.get_power().output_milliamps - 196.0).abs() < 1e-3);
+        zones[1].0.advance(1);
+        zones[0].0.increment_ticks();
+
+        // Both zones get the same share once the first one has caught up
+        for (manager, capture) in zones.iter() {
+            assert!((manager.get_power().output_milliamps - 100.0).abs() < 1e-3);
+            assert_eq!(capture.last().unwrap().leds[0], Color::rgb(102, 102, 102));
+        }
+    }
+
+    #[test]
+    fn composites_layers_with_their_blend_modes() {
+        let (mut manager, capture) = manager(1);
+        manager.add_pattern("base".to_string(), Box::new(SolidPattern::new(Color::rgb(200, 100, 0))));
+        manager.add_pattern("top".to_string(), Box::new(SolidPattern::new(Color::rgb(200, 0, 0))));
+        manager.advance(1);
+        // Added past full, then scaled back down keeping the hue
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(255, 64, 0)]);
+
+        manager.set_blend("top", BlendMode::Multiply, 1.0);
+        manager.tick();
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(157, 0, 0)]);
+
+        manager.set_blend("top", BlendMode::Max, 0.0);
+        manager.tick();
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(200, 100, 0)]);
+    }
+
+    // Three opaque layers, so the frame is the colour of whichever is on top
+    fn stacked_manager() -> (PatternManager, FrameCapture) {
+        let (mut manager, capture) = manager(1);
+        for &(name, color) in [("red", Color::rgb(255, 0, 0)), ("green", Color::rgb(0, 255, 0)), ("blue", Color::rgb(0, 0, 255))].iter() {
+            manager.add_pattern(name.to_string(), Box::new(SolidPattern::new(color)));
+            manager.set_blend(name, BlendMode::AlphaOver, 1.0);
+        }
+        manager.advance(1);
+        (manager, capture)
+    }
+
+    #[test]
+    fn raises_and_lowers_layers() {
+        let (mut manager, capture) = stacked_manager();
+        assert_eq!(manager.get_patterns(), vec!["red", "green", "blue"]);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 0, 255)]);
+
+        assert!(manager.raise_pattern("red"));
+        assert_eq!(manager.get_patterns(), vec!["green", "red", "blue"]);
+        assert!(manager.raise_pattern("red"));
+        assert_eq!(manager.get_patterns(), vec!["green", "blue", "red"]);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(255, 0, 0)]);
+
+        // Already at the top or bottom, so nothing moves
+        assert!(manager.raise_pattern("red"));
+        assert!(manager.lower_pattern("green"));
+        assert_eq!(manager.get_patterns(), vec!["green", "blue", "red"]);
+
+        assert!(manager.lower_pattern("red"));
+        assert_eq!(manager.get_patterns(), vec!["green", "red", "blue"]);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 0, 255)]);
+
+        assert!(!manager.raise_pattern("missing"));
+        assert!(!manager.lower_pattern("missing"));
+    }
+
+    #[test]
+    fn keeps_the_order_of_tied_z_indexes() {
+        let (mut manager, capture) = stacked_manager();
+        // Joins red at 0 without passing it, so green is still above
+        assert!(manager.set_z_index("blue", 0));
+        assert_eq!(manager.get_patterns(), vec!["red", "blue", "green"]);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 255, 0)]);
+
+        assert!(manager.set_z_index("green", 0));
+        assert_eq!(manager.get_patterns(), vec!["red", "blue", "green"]);
+        assert!(manager.set_z_index("red", 1));
+        assert_eq!(manager.get_patterns(), vec!["blue", "green", "red"]);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(255, 0, 0)]);
+
+        assert!(manager.set_z_index("red", -1));
+        assert_eq!(manager.get_patterns(), vec!["red", "blue", "green"]);
+        assert!(!manager.set_z_index("missing", 0));
+        let z_indexes: Vec<i32> = manager.get_state().layers.iter().map(|layer| layer.z_index).collect();
+        assert_eq!(z_indexes, vec![-1, 0, 0]);
+    }
+
+    #[test]
+    fn layers_only_draw_on_their_region() {
+        let (mut manager, capture) = manager(4);
+        let red = Color::rgb(255, 0, 0);
+        manager.add_pattern_with_region("segment".to_string(), Box::new(SolidPattern::new(red)), Region::Mask { mask: vec![3, 1] });
+        manager.add_pattern_with_region("off strip".to_string(), Box::new(SolidPattern::new(red)), Region::Mask { mask: vec![9] });
+        manager.advance(1);
+        assert_eq!(capture.last().unwrap().leds, vec![Color::BLACK, red, Color::BLACK, red]);
+    }
+
+    #[test]
+    fn transitions_between_pattern_sets() {
+        let (mut manager, capture) = manager(1);
+        manager.add_pattern("red".to_string(), Box::new(SolidPattern::new(Color::rgb(255, 0, 0))));
+        manager.advance(1);
+
+        manager.start_transition(TransitionStyle::Crossfade, time::Duration::from_secs(60));
+        manager.add_pattern("green".to_string(), Box::new(SolidPattern::new(Color::rgb(0, 255, 0))));
+        manager.advance(1);
+        // Barely started, so still almost entirely the old set
+        assert!(capture.last().unwrap().leds[0].r > 250);
+
+        manager.start_transition(TransitionStyle::Crossfade, time::Duration::from_secs(0));
+        manager.add_pattern("blue".to_string(), Box::new(SolidPattern::new(Color::rgb(0, 0, 255))));
+        manager.advance(1);
+        manager.increment_ticks();
+        assert_eq!(capture.last().unwrap().leds, vec![Color::rgb(0, 0, 255)]);
+        assert_eq!(manager.get_patterns(), vec!["blue".to_string()]);
+    }
+
+    #[test]
+    fn restarting_a_transition_fades_from_the_mixed_frame() {
+        let (mut manager, capture) = manager(1);
+        manager.add_pattern("red".to_string(), Box::new(SolidPattern::new(Color::rgb(255, 0, 0))));
+        manager.advance(1);
+        manager.start_transition(TransitionStyle::Crossfade, time::Duration::from_secs(60));
+        manager.add_pattern("green".to_string(), Box::new(SolidPattern::new(Color::rgb(0, 255, 0))));
+        manager.advance(1);
 
-        self.controller.render()
-            .expect("Failed to render leds");
+        // Starting over mid-fade carries on from what was showing rather than cutting to green
+        manager.start_transition(TransitionStyle::Crossfade, time::Duration::from_secs(60));
+        manager.add_pattern("blue".to_string(), Box::new(SolidPattern::new(Color::rgb(0, 0, 255))));
+        manager.advance(1);
+        let led = capture.last().unwrap().leds[0];
+        assert!(led.r > 250 && led.g < 5, "{:?}", led);
     }
 }
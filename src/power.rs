@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+// Rough current model of the strip, used to keep full frames within what the supply can
+// deliver. The defaults are typical for 5V WS2811/WS2812 LEDs.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct PowerModel {
+    // Draw of each channel of one LED at full output
+    pub channel_milliamps: ChannelCurrent,
+    // Draw of one LED that is completely off
+    pub idle_milliamps: f32,
+    // Supply budget for the whole strip, shared by its zones, unlimited when unset
+    pub limit_milliamps: Option<f32>,
+}
+
+impl Default for PowerModel {
+    fn default() -> PowerModel {
+        PowerModel {
+            channel_milliamps: ChannelCurrent::default(),
+            idle_milliamps: 1.0,
+            limit_milliamps: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct ChannelCurrent {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub w: f32,
+}
+
+impl Default for ChannelCurrent {
+    fn default() -> ChannelCurrent {
+        ChannelCurrent { r: 20.0, g: 20.0, b: 20.0, w: 20.0 }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Default)]
+pub struct PowerReport {
+    // What the frame would have drawn without limiting
+    pub estimated_milliamps: f32,
+    // What the frame draws after limiting
+    pub output_milliamps: f32,
+    // What the latest frames of every zone sharing the budget would draw without limiting
+    pub total_milliamps: f32,
+    pub limit_milliamps: Option<f32>,
+    // Factor the frame was scaled by, 1 when under budget
+    pub scale: f32,
+}
+
+impl PowerModel {
+    // Takes [r, g, b, w] channels in the 0 to 255 range
+    pub fn estimate(&self, frame: &[[f32; 4]]) -> f32 {
+        let current = [
+            self.channel_milliamps.r,
+            self.channel_milliamps.g,
+            self.channel_milliamps.b,
+            self.channel_milliamps.w,
+        ];
+        let mut total = self.idle_milliamps * frame.len() as f32;
+        for led in frame {
+            for (value, milliamps) in led.iter().zip(current.iter()) {
+                total += value / 255.0 * milliamps;
+            }
+        }
+        total
+    }
+
+    // Scales the frame down in place so its estimated draw fits the limit
+    pub fn limit(&self, frame: &mut [[f32; 4]]) -> PowerReport {
+        let estimated = self.estimate(frame);
+        let idle = self.idle_milliamps * frame.len() as f32;
+        self.scale_within(frame, (estimated, idle), (estimated, idle))
+    }
+
+    // Like limit, but the limit covers this frame together with the latest frames of the
+    // other zones sharing the budget, all of which are scaled by the same factor
+    pub fn limit_shared(&self, frame: &mut [[f32; 4]], share: &mut PowerShare) -> PowerReport {
+        let estimated = self.estimate(frame);
+        let idle = self.idle_milliamps * frame.len() as f32;
+        let total = share.update(estimated, idle);
+        self.scale_within(frame, (estimated, idle), total)
+    }
+
+    // Takes the estimated and idle draw of the frame and of everything the limit covers
+    fn scale_within(&self, frame: &mut [[f32; 4]], own: (f32, f32), total: (f32, f32)) -> PowerReport {
+        let (estimated, total_idle) = (own.0, total.1);
+        let mut report = PowerReport {
+            estimated_milliamps: estimated,
+            output_milliamps: estimated,
+            total_milliamps: total.0,
+            limit_milliamps: self.limit_milliamps,
+            scale: 1.0,
+        };
+
+        if let Some(limit) = self.limit_milliamps {
+            if total.0 > limit {
+                // The idle draw can't be scaled away, only what the channels add on top of it
+                let scale = ((limit - total_idle) / (total.0 - total_idle)).clamp(0.0, 1.0);
+                for led in frame.iter_mut() {
+                    for channel in led.iter_mut() {
+                        *channel *= scale;
+                    }
+                }
+                report.scale = scale;
+                report.output_milliamps = self.estimate(frame);
+            }
+        }
+        report
+    }
+}
+
+// One limit for zones that run from the same supply. Clones refer to the same budget.
+#[derive(Clone, Default)]
+pub struct PowerBudget {
+    shared: Arc<Mutex<SharedDraw>>,
+}
+
+#[derive(Default)]
+struct SharedDraw {
+    // Estimated and idle draw of each zone's latest frame
+    zones: Vec<(f32, f32)>,
+    // Bumped whenever a zone's estimate changes, so the others know to rescale
+    generation: u64,
+}
+
+impl PowerBudget {
+    pub fn new() -> PowerBudget {
+        PowerBudget::default()
+    }
+
+    // Adds a zone to the budget
+    pub fn join(&self) -> PowerShare {
+        let mut shared = lock(&self.shared);
+        shared.zones.push((0.0, 0.0));
+        PowerShare {
+            shared: Arc::clone(&self.shared),
+            index: shared.zones.len() - 1,
+            seen: shared.generation,
+        }
+    }
+}
+
+// A zone's place in a PowerBudget
+pub struct PowerShare {
+    shared: Arc<Mutex<SharedDraw>>,
+    index: usize,
+    seen: u64,
+}
+
+impl PowerShare {
+    // Whether another zone's draw changed since this one last limited a frame, in which case
+    // its frame is scaled for a total that no longer holds
+    pub fn is_stale(&self) -> bool {
+        lock(&self.shared).generation != self.seen
+    }
+
+    // Records this zone's draw and returns the estimated and idle draw of all the zones
+    fn update(&mut self, estimated: f32, idle: f32) -> (f32, f32) {
+        let mut shared = lock(&self.shared);
+        if shared.zones[self.index] != (estimated, idle) {
+            shared.zones[self.index] = (estimated, idle);
+            shared.generation += 1;
+        }
+        self.seen = shared.generation;
+        shared.zones.iter().fold((0.0, 0.0), |total, zone| (total.0 + zone.0, total.1 + zone.1))
+    }
+}
+
+fn lock(shared: &Mutex<SharedDraw>) -> MutexGuard<'_, SharedDraw> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(limit: f32) -> PowerModel {
+        PowerModel { limit_milliamps: Some(limit), ..PowerModel::default() }
+    }
+
+    #[test]
+    fn estimates_idle_and_channel_draw() {
+        let model = PowerModel::default();
+        assert_eq!(model.estimate(&[[0.0; 4]; 10]), 10.0);
+        assert_eq!(model.estimate(&[[255.0, 127.5, 0.0, 255.0]]), 1.0 + 20.0 + 10.0 + 20.0);
+    }
+
+    #[test]
+    fn leaves_frames_under_the_limit_alone() {
+        let mut frame = [[255.0, 0.0, 0.0, 0.0]; 2];
+        let report = limited(100.0).limit(&mut frame);
+        assert_eq!(frame, [[255.0, 0.0, 0.0, 0.0]; 2]);
+        assert_eq!(report.scale, 1.0);
+        assert_eq!(report.output_milliamps, 42.0);
+        assert_eq!(report.total_milliamps, 42.0);
+    }
+
+    #[test]
+    fn scales_only_the_draw_above_idle() {
+        // 4 idle plus 240 from the channels, limited to 64
+        let mut frame = [[255.0, 255.0, 255.0, 0.0]; 4];
+        let report = limited(64.0).limit(&mut frame);
+        assert_eq!(report.estimated_milliamps, 244.0);
+        assert!((report.scale - 0.25).abs() < 1e-6);
+        assert!((report.output_milliamps - 64.0).abs() < 1e-3);
+        assert!((frame[0][0] - 63.75).abs() < 1e-3);
+
+        // A limit under the idle draw turns everything off
+        let mut frame = [[255.0; 4]; 4];
+        assert_eq!(limited(2.0).limit(&mut frame).scale, 0.0);
+        assert_eq!(frame, [[0.0; 4]; 4]);
+    }
+
+    #[test]
+    fn shares_one_limit_between_zones() {
+        let budget = PowerBudget::new();
+        let model = limited(64.0);
+        let (mut first, mut second) = (budget.join(), budget.join());
+
+        let mut bright = [[255.0, 255.0, 255.0, 0.0]; 2];
+        let report = model.limit_shared(&mut bright, &mut first);
+        assert_eq!(report.total_milliamps, 122.0);
+        assert!(!first.is_stale());
+
+        // The second zone's frame counts against the first one's as well
+        let mut bright_too = [[255.0, 255.0, 255.0, 0.0]; 2];
+        let report = model.limit_shared(&mut bright_too, &mut second);
+        assert_eq!(report.total_milliamps, 244.0);
+        assert!((report.scale - 0.25).abs() < 1e-6);
+        assert!(first.is_stale());
+
+        let mut bright = [[255.0, 255.0, 255.0, 0.0]; 2];
+        let first_report = model.limit_shared(&mut bright, &mut first);
+        assert!(!first.is_stale());
+        assert!(!second.is_stale());
+        assert!((first_report.output_milliamps + report.output_milliamps - 64.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error;
+use serde_json::{Map, Value};
+
+// The part of the strip a layer draws on. The layer's buffer is as long as the region and
+// every pixel of it is mapped onto one or more pixels of the strip. An object with a
+// "mask" key is a mask, anything else a segment.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Region {
+    // Any set of strip pixels, in the order the layer should draw them
+    Mask { mask: Vec<usize> },
+    Segment(Segment),
+}
+
+impl Default for Region {
+    fn default() -> Region {
+        Region::Segment(Segment::default())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Segment {
+    pub start: usize,
+    // Runs to the end of the strip when unset
+    pub length: Option<usize>,
+    // Draws the layer from the far end of the segment
+    pub reverse: bool,
+    // Draws the layer twice, out from both ends and meeting in the middle
+    pub mirror: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaskFields { mask: Vec<usize> }
+
+// Picking the variant by key, rather than trying each in turn, keeps a malformed mask from
+// passing as a segment that covers the whole strip
+impl<'de> Deserialize<'de> for Region {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Region, D::Error> {
+        let fields = Map::<String, Value>::deserialize(deserializer)?;
+        if fields.contains_key("mask") {
+            let MaskFields { mask } = serde_json::from_value(Value::Object(fields)).map_err(D::Error::custom)?;
+            Ok(Region::Mask { mask })
+        } else {
+            serde_json::from_value(Value::Object(fields)).map(Region::Segment).map_err(D::Error::custom)
+        }
+    }
+}
+
+impl Region {
+    // The strip pixels each pixel of the layer lands on. Pixels past the end of the strip
+    // are dropped.
+    pub fn map(&self, num_leds: usize) -> Vec<Vec<usize>> {
+        match self {
+            Region::Mask { mask } => {
+                mask.iter().filter(|&&led| led < num_leds).map(|&led| vec![led]).collect()
+            }
+            Region::Segment(segment) => {
+                let start = segment.start.min(num_leds);
+                let available = num_leds - start;
+                let length = segment.length.map_or(available, |length| length.min(available));
+                let mut leds: Vec<usize> = (start..start + length).collect();
+                if segment.reverse {
+                    leds.reverse();
+                }
+
+                if segment.mirror {
+                    let half = length.div_ceil(2);
+                    (0..half)
+                        .map(|i| {
+                            let mut targets = vec![leds[i]];
+                            if length - 1 - i != i {
+                                targets.push(leds[length - 1 - i]);
+                            }
+                            targets
+                        })
+                        .collect()
+                } else {
+                    leds.into_iter().map(|led| vec![led]).collect()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(json: &str) -> Region {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn covers_the_whole_strip_by_default() {
+        assert_eq!(Region::default().map(3), vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(region("{}"), Region::default());
+    }
+
+    #[test]
+    fn maps_segments() {
+        assert_eq!(region(r#"{"start": 2, "length": 3}"#).map(10), vec![vec![2], vec![3], vec![4]]);
+        assert_eq!(region(r#"{"start": 7, "reverse": true}"#).map(10), vec![vec![9], vec![8], vec![7]]);
+    }
+
+    #[test]
+    fn mirrors_from_both_ends() {
+        let mirrored = region(r#"{"start": 2, "length": 5, "mirror": true, "reverse": true}"#);
+        assert_eq!(mirrored.map(10), vec![vec![6, 2], vec![5, 3], vec![4]]);
+        let even = region(r#"{"length": 4, "mirror": true}"#);
+        assert_eq!(even.map(10), vec![vec![0, 3], vec![1, 2]]);
+    }
+
+    #[test]
+    fn clips_to_the_strip() {
+        assert_eq!(region(r#"{"start": 8, "length": 5}"#).map(10), vec![vec![8], vec![9]]);
+        assert!(region(r#"{"start": 20, "mirror": true}"#).map(10).is_empty());
+        assert!(region(r#"{"length": 0, "mirror": true}"#).map(10).is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_regions() {
+        for json in [r#"{"mask": [1, -2]}"#, r#"{"mask": "1,2"}"#, r#"{"mask": [1], "start": 2}"#, r#"{"strat": 5}"#, r#"{"start": 2, "lenght": 3}"#, "[1, 2]"].iter() {
+            assert!(serde_json::from_str::<Region>(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        for region in [Region::Mask { mask: vec![3, 1] }, region(r#"{"start": 2, "length": 3, "mirror": true}"#)].iter() {
+            assert_eq!(&serde_json::from_value::<Region>(serde_json::to_value(region).unwrap()).unwrap(), region);
+        }
+    }
+
+    #[test]
+    fn maps_masks_in_order() {
+        let mask = region(r#"{"mask": [9, 0, 20, 4]}"#);
+        assert_eq!(mask, Region::Mask { mask: vec![9, 0, 20, 4] });
+        assert_eq!(mask.map(10), vec![vec![9], vec![0], vec![4]]);
+    }
+}
@@ -0,0 +1,160 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::pattern::{self, Pattern, PatternArgs, ParamSchema, MovingRainbow, SolidTimeVaryingRainbow, GradientPattern, SolidPattern, FadePattern, FadingCrawl};
+use crate::pattern::{RainbowArgs, GradientArgs, SolidArgs, FadeArgs, FadeCrawlArgs};
+
+type Factory = Box<dyn Fn(Value) -> Result<Box<dyn Pattern>, String> + Send + Sync>;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownPattern(String),
+    InvalidArgs(String, String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::UnknownPattern(name) => write!(f, "unknown pattern type {:?}", name),
+            RegistryError::InvalidArgs(name, err) => write!(f, "invalid args for {}: {}", name, err),
+        }
+    }
+}
+
+// Maps the pattern type names used in commands to the patterns they build. Every type
+// registers the args it deserializes and a factory that turns them into the pattern.
+pub struct PatternRegistry {
+    patterns: BTreeMap<String, Registered>,
+}
+
+struct Registered {
+    factory: Factory,
+    params: Vec<ParamSchema>,
+}
+
+impl PatternRegistry {
+    // A registry without any patterns, see Default for one with the built in patterns
+    pub fn new() -> PatternRegistry {
+        PatternRegistry { patterns: BTreeMap::new() }
+    }
+
+    pub fn register<A, P, F>(&mut self, name: &str, factory: F)
+    where
+        A: PatternArgs,
+        P: Pattern + 'static,
+        F: Fn(A) -> P + Send + Sync + 'static,
+    {
+        let factory = move |args: Value| -> Result<Box<dyn Pattern>, String> {
+            Ok(Box::new(factory(pattern::parse_args(args)?)))
+        };
+        self.patterns.insert(name.to_string(), Registered { factory: Box::new(factory), params: A::schema() });
+    }
+
+    pub fn build(&self, name: &str, args: Value) -> Result<Box<dyn Pattern>, RegistryError> {
+        let registered = self.patterns.get(name).ok_or_else(|| RegistryError::UnknownPattern(name.to_string()))?;
+        (registered.factory)(args).map_err(|err| RegistryError::InvalidArgs(name.to_string(), err))
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.patterns.keys().cloned().collect()
+    }
+
+    // The parameters of every pattern type, keyed by type name
+    pub fn describe(&self) -> BTreeMap<&str, &[ParamSchema]> {
+        self.patterns.iter().map(|(name, registered)| (name.as_str(), registered.params.as_slice())).collect()
+    }
+}
+
+impl Default for PatternRegistry {
+    fn default() -> PatternRegistry {
+        let mut registry = PatternRegistry::new();
+        registry.register("moving_rainbow", |args: RainbowArgs| {
+            MovingRainbow::new(args.tick_rate, args.saturation, args.lightness, args.brightness)
+        });
+        registry.register("solid_rainbow", |args: RainbowArgs| {
+            SolidTimeVaryingRainbow::new(args.tick_rate, args.saturation, args.lightness, args.brightness)
+        });
+        registry.register("gradient", |args: GradientArgs| GradientPattern::new(args.start_color, args.end_color));
+        registry.register("solid", |args: SolidArgs| SolidPattern::new(args.color));
+        registry.register("fade", |args: FadeArgs| {
+            let pattern = FadePattern::new(args.tick_rate, args.color, args.num_dots);
+            match args.seed {
+                Some(seed) => pattern.seed(seed),
+                None => pattern,
+            }
+        });
+        registry.register("fade_crawl", |args: FadeCrawlArgs| {
+            FadingCrawl::new(args.tick_rate, args.tail_len, args.color, args.start_pos)
+        });
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PRESETS: [&str; 7] = [
+        include_str!("../relay/patterns/base.json"),
+        include_str!("../relay/patterns/basic_gradient.json"),
+        include_str!("../relay/patterns/christmas.json"),
+        include_str!("../relay/patterns/crawlers.json"),
+        include_str!("../relay/patterns/many_sparkles.json"),
+        include_str!("../relay/patterns/moving_rainbow.json"),
+        include_str!("../relay/patterns/rainbow.json"),
+    ];
+
+    #[test]
+    fn builds_patterns_from_defaults() {
+        let registry = PatternRegistry::default();
+        for name in registry.names() {
+            let pattern = registry.build(&name, json!({})).unwrap();
+            assert!(pattern.params().is_object(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_types_and_args() {
+        let registry = PatternRegistry::default();
+        assert!(matches!(registry.build("sparkles", json!({})), Err(RegistryError::UnknownPattern(_))));
+        match registry.build("fade", json!({ "num_leds": 3 })) {
+            Err(RegistryError::InvalidArgs(name, err)) => {
+                assert_eq!(name, "fade");
+                assert!(err.contains("num_leds"));
+            }
+            _ => panic!("expected num_leds to be rejected"),
+        }
+        assert!(registry.build("fade_crawl", json!({ "tail_len": 0 })).is_err());
+        assert!(registry.build("solid", json!({ "color": "nope" })).is_err());
+    }
+
+    #[test]
+    fn describes_params_with_defaults() {
+        let registry = PatternRegistry::default();
+        let described = json!(registry.describe());
+        let fade = described["fade"].as_array().unwrap();
+        let num_dots = fade.iter().find(|param| param["name"] == "num_dots").unwrap();
+        assert_eq!(num_dots["type"], "integer");
+        assert_eq!(num_dots["default"], 40);
+        assert_eq!(num_dots["units"], "leds");
+        let seed = fade.iter().find(|param| param["name"] == "seed").unwrap();
+        assert_eq!(seed["default"], Value::Null);
+    }
+
+    #[test]
+    fn builds_every_relay_preset() {
+        let registry = PatternRegistry::default();
+        for preset in PRESETS.iter() {
+            let layers: BTreeMap<String, Value> = serde_json::from_str(preset).unwrap();
+            for (name, layer) in layers {
+                let pattern_type = layer["pattern"].as_str().unwrap();
+                let args = layer.get("args").cloned().unwrap_or_else(|| json!({}));
+                if let Err(err) = registry.build(pattern_type, args) {
+                    panic!("preset {} doesn't build: {}", name, err);
+                }
+            }
+        }
+    }
+}
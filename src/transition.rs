@@ -0,0 +1,118 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time;
+
+// How the outgoing layers give way to the incoming ones
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionStyle {
+    // Fade every pixel from the old frame to the new one at once
+    #[default]
+    Crossfade,
+    // Sweep the new frame in from the start of the strip
+    Wipe,
+    // Switch pixels over one by one in a random order
+    Dissolve,
+}
+
+pub struct Transition {
+    style: TransitionStyle,
+    start: time::Instant,
+    duration: time::Duration,
+    // When each pixel switches over, as a fraction of the transition
+    thresholds: Vec<f32>,
+}
+
+impl Transition {
+    pub fn new(style: TransitionStyle, duration: time::Duration, num_leds: usize) -> Transition {
+        let thresholds = match style {
+            TransitionStyle::Crossfade => Vec::new(),
+            TransitionStyle::Wipe => (0..num_leds).map(|i| i as f32 / num_leds as f32).collect(),
+            TransitionStyle::Dissolve => {
+                let mut rng = rand::thread_rng();
+                (0..num_leds).map(|_| rng.gen::<f32>()).collect()
+            }
+        };
+        Transition { style, start: time::Instant::now(), duration, thresholds }
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.duration.as_secs_f32() <= 0.0 {
+            return 1.0;
+        }
+        (self.start.elapsed().as_secs_f32() / self.duration.as_secs_f32()).min(1.0)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress() >= 1.0
+    }
+
+    // Mixes the frames of the outgoing and incoming layers into `from`
+    pub fn mix(&self, from: &mut [[f32; 4]], to: &[[f32; 4]]) {
+        let progress = self.progress();
+        for (i, (old, new)) in from.iter_mut().zip(to.iter()).enumerate() {
+            let amount = match self.style {
+                TransitionStyle::Crossfade => progress,
+                _ if progress > self.thresholds[i] => 1.0,
+                _ => 0.0,
+            };
+            for (old, new) in old.iter_mut().zip(new.iter()) {
+                *old += (new - *old) * amount;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A transition the given fraction of the way through
+    fn at(style: TransitionStyle, progress: f32, num_leds: usize) -> Transition {
+        let mut transition = Transition::new(style, time::Duration::from_secs(100), num_leds);
+        transition.start = time::Instant::now() - time::Duration::from_secs_f32(100.0 * progress);
+        transition
+    }
+
+    fn mixed(transition: &Transition, num_leds: usize) -> Vec<[f32; 4]> {
+        let mut from = vec![[1.0, 0.0, 0.0, 0.0]; num_leds];
+        transition.mix(&mut from, &vec![[0.0, 1.0, 0.0, 0.0]; num_leds]);
+        from
+    }
+
+    #[test]
+    fn crossfades_every_pixel_at_once() {
+        let transition = at(TransitionStyle::Crossfade, 0.25, 2);
+        for led in mixed(&transition, 2) {
+            assert!((led[0] - 0.75).abs() < 0.01 && (led[1] - 0.25).abs() < 0.01, "{:?}", led);
+        }
+        assert!(!transition.is_done());
+    }
+
+    #[test]
+    fn wipes_from_the_start() {
+        let transition = at(TransitionStyle::Wipe, 0.4, 4);
+        let switched: Vec<bool> = mixed(&transition, 4).iter().map(|led| led[1] == 1.0).collect();
+        assert_eq!(switched, vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn dissolves_pixels_one_by_one() {
+        let transition = Transition::new(TransitionStyle::Dissolve, time::Duration::from_secs(1), 50);
+        assert_eq!(transition.thresholds.len(), 50);
+        assert!(transition.thresholds.iter().all(|&threshold| (0.0..1.0).contains(&threshold)));
+        // Every pixel is wholly old or new
+        for led in mixed(&at(TransitionStyle::Dissolve, 0.5, 50), 50) {
+            assert!(led == [1.0, 0.0, 0.0, 0.0] || led == [0.0, 1.0, 0.0, 0.0]);
+        }
+        let done = mixed(&at(TransitionStyle::Dissolve, 1.0, 50), 50);
+        assert!(done.iter().all(|led| *led == [0.0, 1.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn zero_duration_is_done_at_once() {
+        let transition = Transition::new(TransitionStyle::Wipe, time::Duration::from_secs(0), 3);
+        assert_eq!(transition.progress(), 1.0);
+        assert!(transition.is_done());
+    }
+}
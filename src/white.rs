@@ -0,0 +1,112 @@
+use serde::Deserialize;
+
+use crate::color::Color;
+
+// How the white die of an RGBW strip is driven from the composited colour. Any white
+// a pattern sets explicitly is always kept and the extracted white is added to it.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum WhiteMode {
+    // Leave the RGB channels alone
+    None,
+    // Move the part common to r, g and b onto the white channel
+    #[default]
+    Min,
+    // Like Min, but accounts for the tint of the white LED so the colour doesn't shift
+    Temperature { kelvin: f64 },
+}
+
+impl WhiteMode {
+    pub fn extract(&self, led: Color) -> Color {
+        let channels = self.extract_channels(led.channels().map(f32::from));
+        Color::from_channels(channels.map(|value| value.round().clamp(0.0, 255.0) as u8))
+    }
+
+    // Same as extract on [r, g, b, w] channels in the 0 to 255 range, keeping fractions
+    pub fn extract_channels(&self, led: [f32; 4]) -> [f32; 4] {
+        let [r, g, b, w] = led;
+        match *self {
+            WhiteMode::None => led,
+            WhiteMode::Min => {
+                let white = r.min(g).min(b);
+                [r - white, g - white, b - white, (w + white).min(255.0)]
+            }
+            WhiteMode::Temperature { kelvin } => {
+                let tint = white_point(kelvin).map(|channel| channel as f32);
+                let white = (r / tint[0]).min(g / tint[1]).min(b / tint[2]).min(255.0);
+                [
+                    (r - white * tint[0]).max(0.0),
+                    (g - white * tint[1]).max(0.0),
+                    (b - white * tint[2]).max(0.0),
+                    (w + white).min(255.0),
+                ]
+            }
+        }
+    }
+}
+
+// Approximate colour of a black body at `kelvin` as [r, g, b] scaled so the brightest
+// channel is 1. Based on Tanner Helland's fit, good from about 1000K to 40000K.
+fn white_point(kelvin: f64) -> [f64; 3] {
+    let temp = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let r = if temp <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (temp - 60.0).powf(-0.1332047592)
+    };
+    let g = if temp <= 66.0 {
+        99.4708025861 * temp.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (temp - 60.0).powf(-0.0755148492)
+    };
+    let b = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (temp - 10.0).ln() - 305.0447927307
+    };
+
+    let rgb = [r.clamp(0.0, 255.0), g.clamp(0.0, 255.0), b.clamp(0.0, 255.0)];
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    // A channel at zero can never be matched, so keep it just above to avoid dividing by it
+    [(rgb[0] / max).max(1e-3), (rgb[1] / max).max(1e-3), (rgb[2] / max).max(1e-3)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_moves_the_common_part_to_white() {
+        assert_eq!(WhiteMode::Min.extract(Color::rgbw(200, 150, 100, 0)), Color::rgbw(100, 50, 0, 100));
+        // Explicit white is kept, and the sum stops at full
+        assert_eq!(WhiteMode::Min.extract(Color::rgbw(200, 200, 200, 100)), Color::rgbw(0, 0, 0, 255));
+        assert_eq!(WhiteMode::Min.extract_channels([10.5, 20.0, 30.0, 0.0]), [0.0, 9.5, 19.5, 10.5]);
+    }
+
+    #[test]
+    fn none_leaves_colours_alone() {
+        let led = Color::rgbw(200, 150, 100, 5);
+        assert_eq!(WhiteMode::None.extract(led), led);
+    }
+
+    #[test]
+    fn temperature_accounts_for_the_tint() {
+        let mode: WhiteMode = serde_json::from_str(r#"{"mode": "temperature", "kelvin": 3000}"#).unwrap();
+        // A warm white LED is short on blue, so less of it can be moved over than red
+        let led = mode.extract(Color::rgb(255, 255, 255));
+        assert_eq!((led.r, led.w), (0, 255));
+        assert!(led.b > led.g && led.g > 0);
+
+        // Around 6600K the white point is neutral, so it matches Min
+        let neutral = WhiteMode::Temperature { kelvin: 6600.0 };
+        assert_eq!(neutral.extract(Color::rgb(200, 150, 100)), Color::rgbw(100, 50, 0, 100));
+    }
+
+    #[test]
+    fn defaults_to_min() {
+        assert_eq!(WhiteMode::default(), WhiteMode::Min);
+        assert_eq!(serde_json::from_str::<WhiteMode>(r#"{"mode": "none"}"#).unwrap(), WhiteMode::None);
+    }
+}